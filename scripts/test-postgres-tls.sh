@@ -0,0 +1,110 @@
+#!/bin/sh
+# Checks the --dbssl* options against a throwaway Postgres cluster that only
+# accepts TLS connections, using certificates made up on the spot.
+#
+# Needs the Postgres server binaries (initdb, pg_ctl) and openssl on PATH,
+# and must not run as root. Usage: scripts/test-postgres-tls.sh [SERVER]
+# where SERVER defaults to the debug build.
+
+set -e
+
+SERVER=${1:-target/debug/ikea-spider-experiment-server}
+PORT=${PGPORT_TLS_TEST:-54329}
+DIR=$(mktemp -d)
+FAILED=0
+
+cleanup() {
+    pg_ctl -D "$DIR/data" -m immediate stop >/dev/null 2>&1 || true
+    rm -rf "$DIR"
+}
+trap cleanup EXIT
+
+# A CA that signs the server and client certificates, and an unrelated CA
+# the server certificate must not verify against
+openssl req -x509 -newkey rsa:2048 -nodes -days 1 -subj "/CN=test ca" \
+    -keyout "$DIR/ca.key" -out "$DIR/ca.crt" 2>/dev/null
+openssl req -x509 -newkey rsa:2048 -nodes -days 1 -subj "/CN=other ca" \
+    -keyout "$DIR/other.key" -out "$DIR/other.crt" 2>/dev/null
+
+printf "subjectAltName=DNS:localhost\n" > "$DIR/server.ext"
+openssl req -newkey rsa:2048 -nodes -subj "/CN=localhost" \
+    -keyout "$DIR/server.key" -out "$DIR/server.csr" 2>/dev/null
+openssl x509 -req -days 1 -in "$DIR/server.csr" -CA "$DIR/ca.crt" -CAkey "$DIR/ca.key" \
+    -CAcreateserial -extfile "$DIR/server.ext" -out "$DIR/server.crt" 2>/dev/null
+
+openssl req -newkey rsa:2048 -nodes -subj "/CN=certuser" \
+    -keyout "$DIR/client.key" -out "$DIR/client.csr" 2>/dev/null
+openssl x509 -req -days 1 -in "$DIR/client.csr" -CA "$DIR/ca.crt" -CAkey "$DIR/ca.key" \
+    -CAcreateserial -out "$DIR/client.crt" 2>/dev/null
+chmod 600 "$DIR"/*.key
+
+initdb -D "$DIR/data" -U postgres --auth=trust >/dev/null
+cat >> "$DIR/data/postgresql.conf" <<CONF
+listen_addresses = 'localhost'
+port = $PORT
+unix_socket_directories = '$DIR'
+ssl = on
+ssl_cert_file = '$DIR/server.crt'
+ssl_key_file = '$DIR/server.key'
+ssl_ca_file = '$DIR/ca.crt'
+CONF
+
+# Plain TCP connections are refused, the certuser role also has to present
+# a client certificate
+cat > "$DIR/data/pg_hba.conf" <<CONF
+local all all trust
+hostssl all certuser 127.0.0.1/32 cert
+hostssl all certuser ::1/128 cert
+hostssl all all 127.0.0.1/32 trust
+hostssl all all ::1/128 trust
+CONF
+
+pg_ctl -D "$DIR/data" -l "$DIR/postgres.log" -w start >/dev/null
+
+# The spider's table, empty, so that startup has something to migrate
+psql -q -h "$DIR" -p "$PORT" -U postgres -c "CREATE ROLE certuser LOGIN SUPERUSER" postgres
+psql -q -h "$DIR" -p "$PORT" -U postgres -c "CREATE DATABASE certuser OWNER certuser" postgres
+for db in postgres certuser; do
+    psql -q -h "$DIR" -p "$PORT" -U postgres $db <<SQL
+CREATE TABLE product (
+    id text, name text, typ text, country text, price text, unit text, metric text,
+    url text, image_url text, department text, category text, subcategory text,
+    department_url text, category_url text, subcategory_url text,
+    created_at timestamptz, updated_at timestamptz
+);
+SQL
+done
+
+# expect ok|fail HOST DESCRIPTION ARGS...
+expect() {
+    outcome=$1
+    host=$2
+    description=$3
+    shift 3
+    if "$SERVER" --dbhost "$host" --dbport "$PORT" --dbretries 1 --parse-prices "$@" >"$DIR/out" 2>&1; then
+        result=ok
+    else
+        result=fail
+    fi
+    if [ "$result" = "$outcome" ]; then
+        echo "ok      $description"
+    else
+        echo "FAILED  $description (expected $outcome)"
+        sed 's/^/        /' "$DIR/out"
+        FAILED=1
+    fi
+}
+
+expect fail localhost "disable is refused by a TLS only server" --dbsslmode disable
+expect ok   localhost "prefer negotiates TLS" --dbsslmode prefer
+expect ok   localhost "require does not check the certificate" --dbsslmode require
+expect fail localhost "require with a CA bundle checks the chain" --dbsslmode require --dbsslrootcert "$DIR/other.crt"
+expect ok   localhost "verify-full accepts the right CA and host" --dbsslmode verify-full --dbsslrootcert "$DIR/ca.crt"
+expect fail localhost "verify-full rejects an unknown CA" --dbsslmode verify-full --dbsslrootcert "$DIR/other.crt"
+expect fail 127.0.0.1 "verify-full rejects a host name not in the certificate" --dbsslmode verify-full --dbsslrootcert "$DIR/ca.crt"
+expect fail localhost "a certificate without its key is a configuration error" --dbsslmode require --dbsslcert "$DIR/client.crt"
+expect fail localhost "certuser is refused without a client certificate" --dbuser certuser --dbsslmode require
+expect ok   localhost "client certificates are presented" --dbuser certuser --dbsslmode verify-full --dbsslrootcert "$DIR/ca.crt" \
+    --dbsslcert "$DIR/client.crt" --dbsslkey "$DIR/client.key"
+
+exit $FAILED
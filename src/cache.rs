@@ -0,0 +1,367 @@
+// Cache of read responses in front of the handlers.
+//
+// Responses are kept for a while under their path and normalized query, so
+// queries whose results only change once per crawl, like the taxonomy ones,
+// do not run on every request. Everything is dropped when the product, price
+// history or exchange rate tables change, and can be dropped by hand through the admin
+// endpoint.
+
+use std::collections::HashMap;
+use std::sync::RwLock;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use chrono::*;
+use iron::prelude::*;
+use iron::Handler;
+use iron::response::{ResponseBody, WriteBody};
+use iron::status;
+use iron::typemap::Key;
+use persistent::{Read as PersistentRead, State};
+use url::form_urlencoded;
+
+use db::DatabasePool;
+use error::{ApiError, RowExt};
+
+// How often the tables are checked for changes
+const CHECK_SECS: u64 = 30;
+
+#[derive(Copy, Clone)]
+pub struct ResponseCache;
+
+impl Key for ResponseCache { type Value = Cache; }
+
+// What the cached responses were built from. Parsing the prices of a crawl
+// does not touch updated_at, the count of unparsed prices notices it instead,
+// and the number of history entries notices prices recorded after parsing,
+// whose recorded_at is the product's and can be older than the newest one.
+#[derive(PartialEq)]
+struct Signature {
+    products: i64,
+    products_updated_at: Option<DateTime<UTC>>,
+    unparsed_prices: i64,
+    history_entries: i64,
+    rates_updated_at: Option<DateTime<UTC>>,
+}
+
+struct Entry {
+    status: status::Status,
+    // Kept as text, typed headers cannot be shared between threads
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+    stored_at: Instant,
+}
+
+#[derive(RustcEncodable)]
+pub struct CacheStats {
+    entries: usize,
+    max_entries: usize,
+    bytes: usize,
+    max_bytes: usize,
+    ttl: u64,
+    hits: usize,
+    misses: usize,
+}
+
+pub struct Cache {
+    entries: HashMap<String, Entry>,
+    // 0 turns the cache off
+    max_entries: usize,
+    // Total size of the bodies kept
+    bytes: usize,
+    max_bytes: usize,
+    ttl: Duration,
+    signature: Option<Signature>,
+    checked_at: Option<Instant>,
+    hits: AtomicUsize,
+    misses: AtomicUsize,
+}
+
+impl Cache {
+    pub fn new(max_entries: usize, max_bytes: usize, ttl: Duration) -> Cache {
+        Cache {
+            entries: HashMap::new(),
+            max_entries: max_entries,
+            bytes: 0,
+            max_bytes: max_bytes,
+            ttl: ttl,
+            signature: None,
+            checked_at: None,
+            hits: AtomicUsize::new(0),
+            misses: AtomicUsize::new(0),
+        }
+    }
+
+    // Drops every response, returns how many there were
+    pub fn purge(&mut self) -> usize {
+        let purged = self.entries.len();
+        self.entries.clear();
+        self.bytes = 0;
+        purged
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            entries: self.entries.len(),
+            max_entries: self.max_entries,
+            bytes: self.bytes,
+            max_bytes: self.max_bytes,
+            ttl: self.ttl.as_secs(),
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+
+    fn get(&self, key: &str) -> Option<Response> {
+        let entry = match self.entries.get(key) {
+            Some(entry) if entry.stored_at.elapsed() < self.ttl => entry,
+            _ => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                return None;
+            },
+        };
+        self.hits.fetch_add(1, Ordering::Relaxed);
+
+        let mut response = Response::with((entry.status, entry.body.clone()));
+        for &(ref name, ref value) in &entry.headers {
+            response.headers.set_raw(name.clone(), vec![value.clone().into_bytes()]);
+        }
+        Some(response)
+    }
+
+    // Makes room by dropping expired responses first and then the oldest
+    // ones. A body larger than the whole cache is not kept at all.
+    fn insert(&mut self, key: String, entry: Entry) {
+        let size = entry.body.len();
+        if self.max_entries == 0 || size > self.max_bytes {
+            return;
+        }
+
+        if let Some(replaced) = self.entries.remove(&key) {
+            self.bytes -= replaced.body.len();
+        }
+
+        if !self.fits(size) {
+            let ttl = self.ttl;
+            self.entries.retain(|_, entry| entry.stored_at.elapsed() < ttl);
+            self.bytes = self.entries.values().map(|entry| entry.body.len()).sum();
+        }
+
+        while !self.fits(size) {
+            let oldest = match self.entries.iter().min_by_key(|&(_, entry)| entry.stored_at) {
+                Some((key, _)) => key.clone(),
+                None => break,
+            };
+            if let Some(removed) = self.entries.remove(&oldest) {
+                self.bytes -= removed.body.len();
+            }
+        }
+
+        self.bytes += size;
+        self.entries.insert(key, entry);
+    }
+
+    fn fits(&self, size: usize) -> bool {
+        self.entries.len() < self.max_entries && self.bytes + size <= self.max_bytes
+    }
+}
+
+// Serves the wrapped handler's responses from the cache, successful ones it
+// produces are stored for next time
+pub struct Cached<H>(pub H);
+
+impl<H: Handler> Handler for Cached<H> {
+    fn handle(&self, req: &mut Request) -> IronResult<Response> {
+        let lock = match req.get::<State<ResponseCache>>() {
+            Ok(lock) => lock,
+            Err(_) => return self.0.handle(req),
+        };
+
+        let enabled = lock.read().map(|cache| cache.max_entries > 0).unwrap_or(false);
+        if !enabled {
+            return self.0.handle(req);
+        }
+
+        check_tables(req, &lock);
+
+        let key = cache_key(req);
+        if let Ok(cache) = lock.read() {
+            if let Some(response) = cache.get(&key) {
+                return Ok(response);
+            }
+        }
+
+        let mut response = try!(self.0.handle(req));
+        if response.status != Some(status::Ok) {
+            return Ok(response);
+        }
+
+        let mut body = Vec::new();
+        if let Some(mut writer) = response.body.take() {
+            try!(writer.write_body(&mut ResponseBody::new(&mut body))
+                .map_err(|e| IronError::new(e, status::InternalServerError)));
+        }
+
+        if let Ok(mut cache) = lock.write() {
+            cache.insert(key, Entry {
+                status: status::Ok,
+                headers: response.headers.iter().map(|header| (header.name().to_string(), header.value_string())).collect(),
+                body: body.clone(),
+                stored_at: Instant::now(),
+            });
+        }
+
+        response.body = Some(Box::new(body));
+        Ok(response)
+    }
+}
+
+// The path with the query parameters sorted by name, so that the order they
+// are given in does not matter. Repeated parameters keep their order.
+fn cache_key(req: &Request) -> String {
+    let mut params: Vec<(String, String)> = match req.url.query() {
+        Some(query) => form_urlencoded::parse(query.as_bytes()).into_owned().collect(),
+        None => Vec::new(),
+    };
+    params.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let query = form_urlencoded::Serializer::new(String::new())
+        .extend_pairs(params)
+        .finish();
+
+    format!("/{}?{}", req.url.path().join("/"), query)
+}
+
+// Drops every response once the tables have changed, which is what an
+// import or a crawl does. A failed check is tried again on the next request.
+fn check_tables(req: &mut Request, lock: &RwLock<Cache>) {
+    let due = match lock.read() {
+        Ok(cache) => cache.checked_at.map_or(true, |checked_at| checked_at.elapsed() >= Duration::from_secs(CHECK_SECS)),
+        Err(_) => false,
+    };
+    if !due {
+        return;
+    }
+
+    let signature = match table_signature(req) {
+        Ok(signature) => signature,
+        Err(_) => return,
+    };
+
+    if let Ok(mut cache) = lock.write() {
+        if cache.signature.is_some() && cache.signature.as_ref() != Some(&signature) {
+            let purged = cache.purge();
+            println!("Data changed, dropped {} cached responses", purged);
+        }
+        cache.signature = Some(signature);
+        cache.checked_at = Some(Instant::now());
+    }
+}
+
+fn table_signature(req: &mut Request) -> Result<Signature, ApiError> {
+    // Get database handle
+    let pool = try!(req.get::<PersistentRead<DatabasePool>>());
+    let conn = try!(pool.get());
+
+    let rows = try!(conn.query(
+        "SELECT (SELECT count(*) FROM product),
+                (SELECT max(updated_at) FROM product),
+                (SELECT count(*) FROM product WHERE price_source IS DISTINCT FROM price),
+                (SELECT count(*) FROM price_history),
+                (SELECT max(updated_at) FROM exchange_rate)", &[]));
+    let row = rows.get(0);
+
+    Ok(Signature {
+        products: try!(row.value(0)),
+        products_updated_at: try!(row.value(1)),
+        unparsed_prices: try!(row.value(2)),
+        history_entries: try!(row.value(3)),
+        rates_updated_at: try!(row.value(4)),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::{Duration, Instant};
+
+    use iron::status;
+
+    fn cache(max_entries: usize, max_bytes: usize) -> Cache {
+        Cache::new(max_entries, max_bytes, Duration::from_secs(300))
+    }
+
+    // A response of size bytes stored age seconds ago
+    fn entry(size: usize, age: u64) -> Entry {
+        Entry {
+            status: status::Ok,
+            headers: Vec::new(),
+            body: vec![b'x'; size],
+            stored_at: Instant::now() - Duration::from_secs(age),
+        }
+    }
+
+    fn keys(cache: &Cache) -> Vec<String> {
+        let mut keys: Vec<String> = cache.entries.keys().cloned().collect();
+        keys.sort();
+        keys
+    }
+
+    #[test]
+    fn replacing_an_entry_replaces_its_size() {
+        let mut cache = cache(10, 100);
+        cache.insert("a".to_string(), entry(40, 0));
+        cache.insert("a".to_string(), entry(10, 0));
+        assert_eq!(keys(&cache), vec!["a"]);
+        assert_eq!(cache.bytes, 10);
+    }
+
+    #[test]
+    fn expired_entries_go_before_older_live_ones() {
+        let mut cache = cache(2, 100);
+        cache.insert("live".to_string(), entry(10, 200));
+        cache.insert("expired".to_string(), entry(20, 400));
+        cache.insert("new".to_string(), entry(30, 0));
+        assert_eq!(keys(&cache), vec!["live", "new"]);
+        assert_eq!(cache.bytes, 40);
+    }
+
+    #[test]
+    fn oldest_entries_make_room_for_a_large_body() {
+        let mut cache = cache(10, 100);
+        cache.insert("a".to_string(), entry(40, 30));
+        cache.insert("b".to_string(), entry(40, 20));
+        cache.insert("c".to_string(), entry(10, 10));
+        cache.insert("d".to_string(), entry(60, 0));
+        assert_eq!(keys(&cache), vec!["c", "d"]);
+        assert_eq!(cache.bytes, 70);
+    }
+
+    #[test]
+    fn bodies_larger_than_the_cache_are_not_kept() {
+        let mut cache = cache(10, 100);
+        cache.insert("a".to_string(), entry(40, 0));
+        cache.insert("b".to_string(), entry(101, 0));
+        assert_eq!(keys(&cache), vec!["a"]);
+        assert_eq!(cache.bytes, 40);
+    }
+
+    #[test]
+    fn nothing_is_kept_without_entries() {
+        let mut cache = cache(0, 100);
+        cache.insert("a".to_string(), entry(10, 0));
+        assert!(cache.entries.is_empty());
+        assert_eq!(cache.bytes, 0);
+    }
+
+    #[test]
+    fn purge_empties_the_cache() {
+        let mut cache = cache(10, 100);
+        cache.insert("a".to_string(), entry(10, 0));
+        cache.insert("b".to_string(), entry(20, 0));
+        assert_eq!(cache.purge(), 2);
+        assert!(cache.entries.is_empty());
+        assert_eq!(cache.bytes, 0);
+    }
+}
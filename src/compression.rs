@@ -0,0 +1,147 @@
+// Response compression negotiated with Accept-Encoding.
+//
+// Bodies are buffered and compressed whole, which suits the JSON this server
+// sends. Bodies below the threshold go out as they are, compressing them
+// saves less than it costs.
+
+use std::io::{self, Write};
+
+use brotli2::write::BrotliEncoder;
+use flate2::Compression as GzipLevel;
+use flate2::write::GzEncoder;
+use iron::prelude::*;
+use iron::AfterMiddleware;
+use iron::headers::{AcceptEncoding, ContentEncoding, ContentLength, ContentType, Encoding};
+use iron::mime::{Mime, SubLevel, TopLevel};
+use iron::response::{ResponseBody, WriteBody};
+use iron::status;
+
+use conditional::set_encoded_etag;
+use cors::append_vary;
+
+// Compresses JSON nearly as well as the maximum of 11 at a fraction of the cost
+const BROTLI_QUALITY: u32 = 5;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+enum Coding {
+    Brotli,
+    Gzip,
+}
+
+impl Coding {
+    fn name(&self) -> &'static str {
+        match *self {
+            Coding::Brotli => "br",
+            Coding::Gzip => "gzip",
+        }
+    }
+
+    fn encode(&self, body: &[u8]) -> io::Result<Vec<u8>> {
+        match *self {
+            Coding::Brotli => {
+                let mut encoder = BrotliEncoder::new(Vec::new(), BROTLI_QUALITY);
+                try!(encoder.write_all(body));
+                encoder.finish()
+            },
+            Coding::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), GzipLevel::Default);
+                try!(encoder.write_all(body));
+                encoder.finish()
+            },
+        }
+    }
+}
+
+pub struct Compression {
+    // Smallest body in bytes worth compressing
+    min_size: usize,
+}
+
+impl Compression {
+    pub fn new(min_size: usize) -> Compression {
+        Compression { min_size: min_size }
+    }
+}
+
+impl AfterMiddleware for Compression {
+    fn after(&self, req: &mut Request, mut res: Response) -> IronResult<Response> {
+        // A 304 carries the Vary and the ETag the full response would have
+        // had, the tag of the coding the client would have been sent. The
+        // Content-Length left from the full response tells small bodies apart.
+        if res.status == Some(status::NotModified) {
+            append_vary(&mut res, "Accept-Encoding");
+            let small = res.headers.get::<ContentLength>().map_or(false, |length| (length.0 as usize) < self.min_size);
+            if let Some(coding) = req.headers.get::<AcceptEncoding>().and_then(|accept| negotiate(accept)) {
+                if !small {
+                    set_encoded_etag(&mut res, coding.name());
+                }
+            }
+            return Ok(res);
+        }
+
+        if res.body.is_none() || !compressible(&res) {
+            return Ok(res);
+        }
+
+        // The body depends on Accept-Encoding even when it goes out as it is
+        append_vary(&mut res, "Accept-Encoding");
+
+        let coding = match req.headers.get::<AcceptEncoding>().and_then(|accept| negotiate(accept)) {
+            Some(coding) => coding,
+            None => return Ok(res),
+        };
+
+        let mut body = Vec::new();
+        if let Some(mut writer) = res.body.take() {
+            try!(writer.write_body(&mut ResponseBody::new(&mut body))
+                .map_err(|e| IronError::new(e, status::InternalServerError)));
+        }
+
+        if body.len() < self.min_size {
+            res.body = Some(Box::new(body));
+            return Ok(res);
+        }
+
+        // Setting the body also sets Content-Length to the compressed size
+        let encoded = try!(coding.encode(&body).map_err(|e| IronError::new(e, status::InternalServerError)));
+        res.set_mut(encoded);
+        res.headers.set(ContentEncoding(vec![Encoding::EncodingExt(coding.name().to_string())]));
+        set_encoded_etag(&mut res, coding.name());
+
+        Ok(res)
+    }
+}
+
+// Text and JSON shrink well, anything else is most likely compressed already
+fn compressible(res: &Response) -> bool {
+    if res.headers.has::<ContentEncoding>() {
+        return false;
+    }
+
+    match res.headers.get::<ContentType>() {
+        Some(&ContentType(Mime(TopLevel::Text, _, _))) => true,
+        Some(&ContentType(Mime(TopLevel::Application, SubLevel::Json, _))) => true,
+        _ => false,
+    }
+}
+
+// Picks the coding the client prefers, brotli when it likes both as much.
+// "*" stands for any coding not listed and a quality of 0 rules one out.
+fn negotiate(accept: &AcceptEncoding) -> Option<Coding> {
+    let quality = |name: &str| {
+        let listed = accept.iter().find(|item| item.item.to_string().eq_ignore_ascii_case(name));
+        let any = accept.iter().find(|item| item.item.to_string() == "*");
+        listed.or(any).map_or(0, |item| item.quality.0)
+    };
+
+    let brotli = quality(Coding::Brotli.name());
+    let gzip = quality(Coding::Gzip.name());
+
+    if brotli == 0 && gzip == 0 {
+        None
+    } else if brotli >= gzip {
+        Some(Coding::Brotli)
+    } else {
+        Some(Coding::Gzip)
+    }
+}
@@ -0,0 +1,87 @@
+// Conditional GET for the read endpoints.
+//
+// Responses carry a strong ETag computed from the body and, when the handler
+// knows when its rows last changed, a Last-Modified date. Clients that send
+// either back get a 304 without a body as long as nothing changed.
+
+use std::str;
+
+use chrono::*;
+use iron::prelude::*;
+use iron::AfterMiddleware;
+use iron::headers::{ContentType, ETag, EntityTag, Headers, IfNoneMatch};
+use iron::method::Method;
+use iron::status;
+use openssl::crypto::hash::{hash, Type};
+use rustc_serialize::base64::{ToBase64, URL_SAFE};
+
+// IMF-fixdate, the only date format HTTP/1.1 senders may use
+const HTTP_DATE: &'static str = "%a, %d %b %Y %H:%M:%S GMT";
+
+pub fn etag(body: &[u8]) -> ETag {
+    ETag(EntityTag::strong(hash(Type::SHA1, body).to_base64(URL_SAFE)))
+}
+
+// A compressed body is a different representation and needs a tag of its
+// own, the coding is appended after a dot, which base64 never contains
+pub fn set_encoded_etag(res: &mut Response, coding: &str) {
+    let tag = match res.headers.get::<ETag>() {
+        Some(&ETag(ref tag)) => EntityTag::new(tag.weak, format!("{}.{}", tag.tag(), coding)),
+        None => return,
+    };
+    res.headers.set(ETag(tag));
+}
+
+// The tag of the uncompressed body a tag was derived from
+fn identity_tag(tag: &EntityTag) -> EntityTag {
+    match tag.tag().find('.') {
+        Some(dot) => EntityTag::new(tag.weak, tag.tag()[..dot].to_string()),
+        None => tag.clone(),
+    }
+}
+
+pub fn set_last_modified(res: &mut Response, modified: DateTime<UTC>) {
+    res.headers.set_raw("Last-Modified", vec![modified.format(HTTP_DATE).to_string().into_bytes()]);
+}
+
+pub struct ConditionalGet;
+
+impl AfterMiddleware for ConditionalGet {
+    fn after(&self, req: &mut Request, mut res: Response) -> IronResult<Response> {
+        let safe = req.method == Method::Get || req.method == Method::Head;
+
+        if safe && res.status == Some(status::Ok) && !modified(&req.headers, &res.headers) {
+            res.status = Some(status::NotModified);
+            res.body = None;
+            res.headers.remove::<ContentType>();
+        }
+
+        Ok(res)
+    }
+}
+
+// If-None-Match wins over If-Modified-Since, dates only have a resolution of
+// a second and are the fallback for clients that do not keep ETags
+fn modified(request: &Headers, response: &Headers) -> bool {
+    match (request.get::<IfNoneMatch>(), response.get::<ETag>()) {
+        (Some(&IfNoneMatch::Any), Some(_)) => return false,
+        (Some(&IfNoneMatch::Items(ref tags)), Some(&ETag(ref current))) => {
+            return !tags.iter().any(|tag| identity_tag(tag).weak_eq(current));
+        },
+        (Some(_), None) => return true,
+        (None, _) => {},
+    }
+
+    match (header_date(request, "If-Modified-Since"), header_date(response, "Last-Modified")) {
+        (Some(since), Some(modified)) => modified.timestamp() > since.timestamp(),
+        _ => true,
+    }
+}
+
+fn header_date(headers: &Headers, name: &str) -> Option<DateTime<UTC>> {
+    headers.get_raw(name)
+        .and_then(|values| values.first())
+        .and_then(|value| str::from_utf8(value).ok())
+        .and_then(|value| DateTime::parse_from_rfc2822(value.trim()).ok())
+        .map(|date| date.with_timezone(&UTC))
+}
@@ -0,0 +1,236 @@
+// Server configuration, layered from lowest to highest precedence: built in
+// defaults, a TOML file, IKEA_* environment variables and command line flags.
+//
+// Every setting has a dotted key ("database.host") that is a [database] host
+// entry in the file, IKEA_DATABASE_HOST in the environment and, for most
+// settings, a flag of its own (--dbhost).
+
+use std::collections::BTreeMap;
+use std::env;
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::str::FromStr;
+
+use getopts::Matches;
+use toml;
+
+use error::ApiError;
+
+struct Setting {
+    key: &'static str,
+    flag: Option<&'static str>,
+    default: Option<&'static str>,
+    // Secrets are left out of --print-config
+    secret: bool,
+}
+
+const SETTINGS: &'static [Setting] = &[
+    Setting { key: "database.host", flag: Some("dbhost"), default: Some("localhost"), secret: false },
+    Setting { key: "database.port", flag: Some("dbport"), default: Some("5432"), secret: false },
+    Setting { key: "database.name", flag: Some("dbname"), default: None, secret: false },
+    Setting { key: "database.user", flag: Some("dbuser"), default: Some("postgres"), secret: false },
+    Setting { key: "database.password", flag: Some("dbpass"), default: None, secret: true },
+    Setting { key: "database.password_file", flag: Some("dbpassfile"), default: None, secret: false },
+    Setting { key: "database.sslmode", flag: Some("dbsslmode"), default: Some("disable"), secret: false },
+    Setting { key: "database.sslrootcert", flag: Some("dbsslrootcert"), default: None, secret: false },
+    Setting { key: "database.sslcert", flag: Some("dbsslcert"), default: None, secret: false },
+    Setting { key: "database.sslkey", flag: Some("dbsslkey"), default: None, secret: false },
+    Setting { key: "database.pool_min", flag: Some("dbpoolmin"), default: Some("1"), secret: false },
+    Setting { key: "database.pool_max", flag: Some("dbpoolmax"), default: Some("10"), secret: false },
+    Setting { key: "database.timeout", flag: Some("dbtimeout"), default: Some("5"), secret: false },
+    Setting { key: "database.retries", flag: Some("dbretries"), default: Some("10"), secret: false },
+    Setting { key: "server.host", flag: Some("host"), default: Some("localhost"), secret: false },
+    Setting { key: "server.port", flag: Some("port"), default: Some("8080"), secret: false },
+    Setting { key: "server.tls_cert", flag: Some("tls-cert"), default: None, secret: false },
+    Setting { key: "server.tls_key", flag: Some("tls-key"), default: None, secret: false },
+    Setting { key: "server.http_redirect_port", flag: Some("http-redirect-port"), default: None, secret: false },
+    Setting { key: "cors.allowed_origins", flag: None, default: Some("*"), secret: false },
+    Setting { key: "cors.allowed_methods", flag: None, default: Some("GET, POST"), secret: false },
+    Setting { key: "cors.allowed_headers", flag: None, default: Some("Content-Type"), secret: false },
+    Setting { key: "cors.exposed_headers", flag: None, default: Some("X-Request-Id"), secret: false },
+    Setting { key: "cors.allow_credentials", flag: None, default: Some("false"), secret: false },
+    Setting { key: "cors.max_age", flag: None, default: Some("86400"), secret: false },
+    Setting { key: "compression.min_size", flag: None, default: Some("1024"), secret: false },
+    Setting { key: "cache.max_entries", flag: None, default: Some("1000"), secret: false },
+    Setting { key: "cache.max_bytes", flag: None, default: Some("67108864"), secret: false },
+    Setting { key: "cache.ttl", flag: None, default: Some("300"), secret: false },
+    Setting { key: "admin.token", flag: None, default: None, secret: true },
+];
+
+const ENV_PREFIX: &'static str = "IKEA_";
+
+// Names the config file when --config is not given
+const ENV_CONFIG_FILE: &'static str = "IKEA_CONFIG";
+
+pub struct Config {
+    values: BTreeMap<&'static str, String>,
+}
+
+impl Config {
+    pub fn load(matches: &Matches) -> Result<Config, ApiError> {
+        let mut values = BTreeMap::new();
+
+        for setting in SETTINGS {
+            if let Some(default) = setting.default {
+                values.insert(setting.key, default.to_string());
+            }
+        }
+
+        let path = matches.opt_str("config").or_else(|| env::var(ENV_CONFIG_FILE).ok());
+        if let Some(path) = path {
+            for (key, value) in try!(read_file(&path)) {
+                let setting = try!(find_setting(&key).ok_or(ApiError::Config(format!("{}: unknown setting {}", path, key))));
+                values.insert(setting.key, value);
+            }
+        }
+
+        for (name, value) in env::vars() {
+            if !name.starts_with(ENV_PREFIX) || name == ENV_CONFIG_FILE {
+                continue;
+            }
+            let key = name[ENV_PREFIX.len()..].to_lowercase();
+            let key = match key.find('_') {
+                Some(separator) => format!("{}.{}", &key[..separator], &key[separator + 1..]),
+                None => key,
+            };
+            let setting = try!(find_setting(&key).ok_or(ApiError::Config(format!("unknown setting {}", name))));
+            values.insert(setting.key, value);
+        }
+
+        for setting in SETTINGS {
+            if let Some(value) = setting.flag.and_then(|flag| matches.opt_str(flag)) {
+                values.insert(setting.key, value);
+            }
+        }
+
+        let config = Config { values: values };
+
+        if config.get("database.password").is_some() && config.get("database.password_file").is_some() {
+            return Err(ApiError::Config("set only one of database.password and database.password_file".to_string()));
+        }
+
+        Ok(config)
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.values.get(key).map(|value| value.as_str())
+    }
+
+    pub fn parse<T: FromStr>(&self, key: &str) -> Result<Option<T>, ApiError> {
+        match self.get(key) {
+            Some(value) => match value.parse() {
+                Ok(value) => Ok(Some(value)),
+                Err(_) => Err(ApiError::Config(format!("invalid {} {:?}", key, value))),
+            },
+            None => Ok(None),
+        }
+    }
+
+    // Like parse, for settings that have a default
+    pub fn value<T: FromStr>(&self, key: &str) -> Result<T, ApiError> {
+        match try!(self.parse(key)) {
+            Some(value) => Ok(value),
+            None => Err(ApiError::Config(format!("{} is not set", key))),
+        }
+    }
+
+    // Comma separated values, in the file either a string or an array
+    pub fn list(&self, key: &str) -> Vec<String> {
+        self.get(key).map_or(Vec::new(), |value| {
+            value.split(',')
+                .map(|item| item.trim().to_string())
+                .filter(|item| !item.is_empty())
+                .collect()
+        })
+    }
+
+    // The password, read from database.password_file when that is set so
+    // that it stays out of the process list and the environment
+    pub fn database_password(&self) -> Result<Option<String>, ApiError> {
+        match self.get("database.password_file") {
+            Some(path) => {
+                let mut password = String::new();
+                try!(File::open(path)
+                    .and_then(|mut file| file.read_to_string(&mut password))
+                    .map_err(|e| ApiError::Config(format!("{}: {}", path, e))));
+                Ok(Some(password.trim_right_matches(|c| c == '\n' || c == '\r').to_string()))
+            },
+            None => Ok(self.get("database.password").map(|password| password.to_string())),
+        }
+    }
+}
+
+// Prints the configuration as a config file, with secrets redacted
+impl fmt::Display for Config {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut section = "";
+
+        for setting in SETTINGS {
+            let (table, name) = setting.key.split_at(setting.key.find('.').unwrap());
+            if table != section {
+                try!(write!(f, "{}[{}]\n", if section.is_empty() { "" } else { "\n" }, table));
+                section = table;
+            }
+
+            match self.get(setting.key) {
+                Some(_) if setting.secret => try!(write!(f, "{} = \"<redacted>\"\n", &name[1..])),
+                Some(value) => try!(write!(f, "{} = {}\n", &name[1..], toml::Value::String(value.to_string()))),
+                None => try!(write!(f, "# {} is not set\n", &name[1..])),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn find_setting(key: &str) -> Option<&'static Setting> {
+    SETTINGS.iter().find(|setting| setting.key == key)
+}
+
+// Flattens the tables of a TOML file into dotted keys
+fn read_file(path: &str) -> Result<Vec<(String, String)>, ApiError> {
+    let mut text = String::new();
+    try!(File::open(path)
+        .and_then(|mut file| file.read_to_string(&mut text))
+        .map_err(|e| ApiError::Config(format!("{}: {}", path, e))));
+
+    let mut parser = toml::Parser::new(&text);
+    let table = match parser.parse() {
+        Some(table) => table,
+        None => {
+            let messages: Vec<String> = parser.errors.iter()
+                .map(|error| {
+                    let (line, column) = parser.to_linecol(error.lo);
+                    format!("{}:{}:{}: {}", path, line + 1, column + 1, error.desc)
+                })
+                .collect();
+            return Err(ApiError::Config(messages.join("\n")));
+        },
+    };
+
+    let mut values = Vec::new();
+    for (section, entries) in table {
+        let entries = match entries {
+            toml::Value::Table(entries) => entries,
+            _ => return Err(ApiError::Config(format!("{}: {} is not a table", path, section))),
+        };
+
+        for (name, value) in entries {
+            let value = match value {
+                toml::Value::String(value) => value,
+                toml::Value::Integer(value) => value.to_string(),
+                toml::Value::Float(value) => value.to_string(),
+                toml::Value::Boolean(value) => value.to_string(),
+                toml::Value::Array(ref items) if items.iter().all(|item| item.as_str().is_some()) => {
+                    let items: Vec<&str> = items.iter().filter_map(|item| item.as_str()).collect();
+                    items.join(", ")
+                },
+                _ => return Err(ApiError::Config(format!("{}: {}.{} must be a string, a number or a list of strings", path, section, name))),
+            };
+            values.push((format!("{}.{}", section, name), value));
+        }
+    }
+
+    Ok(values)
+}
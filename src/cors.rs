@@ -0,0 +1,139 @@
+// Cross-origin resource sharing for browser clients.
+//
+// Headers are added to every response, errors included, for origins on the
+// allowlist. Preflight OPTIONS requests are answered by the router with the
+// methods a path supports, this middleware turns those answers into CORS
+// preflight responses.
+
+use iron::prelude::*;
+use iron::AfterMiddleware;
+use iron::headers::{AccessControlAllowCredentials, AccessControlAllowMethods, AccessControlAllowOrigin,
+                    AccessControlMaxAge, AccessControlRequestMethod, Allow};
+use iron::method::Method;
+use iron::status;
+
+pub struct CorsOptions {
+    // "*" allows any origin
+    pub allowed_origins: Vec<String>,
+    pub allowed_methods: Vec<Method>,
+    pub allowed_headers: Vec<String>,
+    pub exposed_headers: Vec<String>,
+    pub allow_credentials: bool,
+    // Seconds browsers may cache a preflight response
+    pub max_age: Option<u32>,
+}
+
+pub struct Cors {
+    options: CorsOptions,
+}
+
+impl Cors {
+    pub fn new(options: CorsOptions) -> Cors {
+        Cors { options: options }
+    }
+
+    // The Access-Control-Allow-Origin value for a request, None when the
+    // origin is not allowed. Credentials cannot be combined with "*", so the
+    // origin is echoed back instead.
+    fn allow_origin(&self, origin: Option<String>) -> Option<AccessControlAllowOrigin> {
+        let any = self.options.allowed_origins.iter().any(|allowed| allowed == "*");
+
+        match origin {
+            Some(origin) => {
+                if any && !self.options.allow_credentials {
+                    Some(AccessControlAllowOrigin::Any)
+                } else if any || self.options.allowed_origins.iter().any(|allowed| allowed.eq_ignore_ascii_case(&origin)) {
+                    Some(AccessControlAllowOrigin::Value(origin))
+                } else {
+                    None
+                }
+            },
+            None if any && !self.options.allow_credentials => Some(AccessControlAllowOrigin::Any),
+            None => None,
+        }
+    }
+
+    fn apply(&self, req: &Request, res: &mut Response) {
+        let origin = req.headers.get_raw("Origin")
+            .and_then(|values| values.first())
+            .and_then(|value| String::from_utf8(value.clone()).ok());
+
+        // Caches must not hand a response for one origin to another
+        if self.options.allowed_origins.iter().any(|allowed| allowed != "*") || self.options.allow_credentials {
+            append_vary(res, "Origin");
+        }
+
+        let allow_origin = match self.allow_origin(origin) {
+            Some(allow_origin) => allow_origin,
+            None => return,
+        };
+        res.headers.set(allow_origin);
+
+        if self.options.allow_credentials {
+            res.headers.set(AccessControlAllowCredentials);
+        }
+
+        let requested_method = req.headers.get::<AccessControlRequestMethod>().map(|method| method.0.clone());
+
+        match requested_method {
+            Some(ref method) if req.method == Method::Options => {
+                // The router lists the methods the path has, the preflight
+                // only succeeds for those that are also allowed here. Without
+                // that list the router did not answer, and its error (such
+                // as a 404 for an unknown path) goes out as it is.
+                let methods: Vec<Method> = match res.headers.get::<Allow>() {
+                    Some(allow) => self.options.allowed_methods.iter().filter(|method| allow.contains(method)).cloned().collect(),
+                    None => return,
+                };
+                if !methods.contains(method) {
+                    res.headers.remove::<AccessControlAllowOrigin>();
+                    res.headers.remove::<AccessControlAllowCredentials>();
+                    return;
+                }
+
+                res.status = Some(status::NoContent);
+                res.headers.set(AccessControlAllowMethods(methods));
+                if !self.options.allowed_headers.is_empty() {
+                    res.headers.set_raw("Access-Control-Allow-Headers", vec![self.options.allowed_headers.join(", ").into_bytes()]);
+                }
+                if let Some(max_age) = self.options.max_age {
+                    res.headers.set(AccessControlMaxAge(max_age));
+                }
+            },
+            _ => {
+                if !self.options.exposed_headers.is_empty() {
+                    res.headers.set_raw("Access-Control-Expose-Headers", vec![self.options.exposed_headers.join(", ").into_bytes()]);
+                }
+            },
+        }
+    }
+}
+
+impl AfterMiddleware for Cors {
+    fn after(&self, req: &mut Request, mut res: Response) -> IronResult<Response> {
+        self.apply(req, &mut res);
+        Ok(res)
+    }
+
+    fn catch(&self, req: &mut Request, mut err: IronError) -> IronResult<Response> {
+        self.apply(req, &mut err.response);
+        Err(err)
+    }
+}
+
+// Adds a field to the Vary header, keeping the ones other middleware set
+pub fn append_vary(res: &mut Response, field: &str) {
+    let mut fields: Vec<String> = match res.headers.get_raw("Vary") {
+        Some(values) => values.iter()
+            .filter_map(|value| String::from_utf8(value.clone()).ok())
+            .flat_map(|value| value.split(',').map(|field| field.trim().to_string()).collect::<Vec<String>>())
+            .filter(|field| !field.is_empty())
+            .collect(),
+        None => Vec::new(),
+    };
+
+    if !fields.iter().any(|existing| existing.eq_ignore_ascii_case(field)) {
+        fields.push(field.to_string());
+    }
+    res.headers.set_raw("Vary", vec![fields.join(", ").into_bytes()]);
+}
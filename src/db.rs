@@ -0,0 +1,158 @@
+// Pool of Postgres connections shared by all handlers.
+//
+// Requests check a connection out for as long as they need it, so a slow
+// query only holds up the requests that are waiting for that connection.
+// Connections that break, for example when Postgres restarts or fails over,
+// are noticed on checkout and replaced by new ones once the database is back.
+
+use std::cmp;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use iron::typemap::Key;
+
+use r2d2::{self, ManageConnection};
+use r2d2_postgres::PostgresConnectionManager;
+
+use error::ApiError;
+use tls::{TlsOptions, ssl_mode};
+
+// The r2d2 pool along with the outage its connection manager tracks, so that
+// a request that times out waiting for a connection can tell a database that
+// is down from one that is merely busy
+#[derive(Clone)]
+pub struct Pool {
+    inner: r2d2::Pool<ConnectionManager>,
+    down_since: Arc<Mutex<Option<Instant>>>,
+}
+
+impl Pool {
+    pub fn get(&self) -> Result<r2d2::PooledConnection<ConnectionManager>, ApiError> {
+        self.inner.get().map_err(|err| {
+            match self.down_since.lock().ok().and_then(|down_since| *down_since) {
+                Some(since) => ApiError::DatabaseUnavailable(format!("no connection for {}s", since.elapsed().as_secs())),
+                None => ApiError::from(err),
+            }
+        })
+    }
+}
+
+const MAX_BACKOFF_SECS: u64 = 30;
+
+#[derive(Copy, Clone)]
+pub struct DatabasePool;
+
+impl Key for DatabasePool { type Value = Pool; }
+
+pub struct PoolOptions {
+    // Idle connections kept open, None keeps max_size of them
+    pub min_idle: Option<u32>,
+    pub max_size: u32,
+    // How long a request waits for a free connection before giving up
+    pub checkout_timeout: Duration,
+    // Attempts to reach the database at startup, 0 retries forever
+    pub connect_attempts: u32,
+}
+
+// Builds the pool, retrying with exponential backoff while the database
+// cannot be reached so that the server can start before Postgres does
+pub fn connect_pool(url: &str, options: &PoolOptions, tls: &TlsOptions) -> Result<Pool, ApiError> {
+    if options.max_size == 0 {
+        return Err(ApiError::Config("the pool needs at least one connection".to_string()));
+    }
+    if options.min_idle.map_or(false, |min_idle| min_idle > options.max_size) {
+        return Err(ApiError::Config("the pool minimum is larger than its maximum".to_string()));
+    }
+
+    let mut attempt = 1;
+    let mut backoff = Duration::from_secs(1);
+
+    loop {
+        // Connections are checked with an empty query when they are checked
+        // out, a connection that died while idle is replaced, not handed out
+        let config = r2d2::Config::builder()
+            .pool_size(options.max_size)
+            .min_idle(options.min_idle)
+            .connection_timeout(options.checkout_timeout)
+            .test_on_check_out(true)
+            .initialization_fail_fast(true)
+            .build();
+
+        let down_since = Arc::new(Mutex::new(None));
+        let manager = ConnectionManager {
+            inner: try!(PostgresConnectionManager::new(url, try!(ssl_mode(tls)))),
+            down_since: down_since.clone(),
+        };
+
+        match r2d2::Pool::new(config, manager) {
+            Ok(pool) => return Ok(Pool { inner: pool, down_since: down_since }),
+            Err(err) => {
+                if options.connect_attempts != 0 && attempt >= options.connect_attempts {
+                    return Err(ApiError::from(err));
+                }
+                println!("Database unavailable (attempt {}), retrying in {}s", attempt, backoff.as_secs());
+                thread::sleep(backoff);
+                attempt += 1;
+                backoff = cmp::min(backoff * 2, Duration::from_secs(MAX_BACKOFF_SECS));
+            },
+        }
+    }
+}
+
+// Wraps the Postgres manager to log when the database goes away and when it
+// comes back. The pool keeps calling connect on behalf of waiting requests,
+// so the first success after a failure marks the end of the outage.
+pub struct ConnectionManager {
+    inner: PostgresConnectionManager,
+    down_since: Arc<Mutex<Option<Instant>>>,
+}
+
+impl ConnectionManager {
+    fn outage(&self, reason: &str) {
+        if let Ok(mut down_since) = self.down_since.lock() {
+            if down_since.is_none() {
+                println!("Database connection lost: {}", reason);
+                *down_since = Some(Instant::now());
+            }
+        }
+    }
+
+    fn recovered(&self) {
+        if let Ok(mut down_since) = self.down_since.lock() {
+            if let Some(since) = down_since.take() {
+                println!("Database connection restored after {}s", since.elapsed().as_secs());
+            }
+        }
+    }
+}
+
+impl ManageConnection for ConnectionManager {
+    type Connection = <PostgresConnectionManager as ManageConnection>::Connection;
+    type Error = <PostgresConnectionManager as ManageConnection>::Error;
+
+    fn connect(&self) -> Result<Self::Connection, Self::Error> {
+        match self.inner.connect() {
+            Ok(conn) => {
+                self.recovered();
+                Ok(conn)
+            },
+            Err(err) => {
+                self.outage(&err.to_string());
+                Err(err)
+            },
+        }
+    }
+
+    fn is_valid(&self, conn: &mut Self::Connection) -> Result<(), Self::Error> {
+        let result = self.inner.is_valid(conn);
+        if let Err(ref err) = result {
+            self.outage(&err.to_string());
+        }
+        result
+    }
+
+    fn has_broken(&self, conn: &mut Self::Connection) -> bool {
+        self.inner.has_broken(conn)
+    }
+}
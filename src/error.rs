@@ -0,0 +1,281 @@
+// Errors returned by the handlers and the startup path.
+//
+// Every error maps to an HTTP status and reaches clients as a JSON body of
+// the form {"code": ..., "message": ..., "request_id": ...}, so that "no
+// such product" and "database down" can be told apart.
+
+use std::error::Error;
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::PoisonError;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use iron::prelude::*;
+use iron::{AfterMiddleware, BeforeMiddleware, Handler};
+use iron::headers::ContentType;
+use iron::modifiers::Header;
+use iron::status;
+use iron::typemap::Key;
+
+use chrono::*;
+use persistent::PersistentError;
+use postgres;
+use postgres::error::ConnectError;
+use postgres::rows::{Row, RowIndex};
+use postgres::types::FromSql;
+use r2d2;
+use rustc_serialize::json;
+
+#[derive(Debug)]
+pub enum ApiError {
+    BadRequest(String),
+    Unauthorized(String),
+    NotFound(String),
+    Database(postgres::error::Error),
+    Connect(ConnectError),
+    // Waiting for a connection timed out while the database is down
+    DatabaseUnavailable(String),
+    Unavailable(String),
+    Config(String),
+    Internal(String),
+}
+
+#[derive(RustcEncodable)]
+struct ErrorBody<'a> {
+    code: &'static str,
+    message: String,
+    request_id: &'a str,
+}
+
+impl ApiError {
+    pub fn status(&self) -> status::Status {
+        match *self {
+            ApiError::BadRequest(_) => status::BadRequest,
+            ApiError::Unauthorized(_) => status::Unauthorized,
+            ApiError::NotFound(_) => status::NotFound,
+            ApiError::Database(postgres::error::Error::Io(_)) => status::ServiceUnavailable,
+            ApiError::Database(_) => status::InternalServerError,
+            ApiError::Connect(_) => status::ServiceUnavailable,
+            ApiError::DatabaseUnavailable(_) => status::ServiceUnavailable,
+            ApiError::Unavailable(_) => status::ServiceUnavailable,
+            ApiError::Config(_) => status::InternalServerError,
+            ApiError::Internal(_) => status::InternalServerError,
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match *self {
+            ApiError::BadRequest(_) => "bad_request",
+            ApiError::Unauthorized(_) => "unauthorized",
+            ApiError::NotFound(_) => "not_found",
+            ApiError::Database(postgres::error::Error::Io(_)) => "database_unavailable",
+            ApiError::Database(_) => "database_error",
+            ApiError::Connect(_) => "database_unavailable",
+            ApiError::DatabaseUnavailable(_) => "database_unavailable",
+            ApiError::Unavailable(_) => "unavailable",
+            ApiError::Config(_) => "configuration_error",
+            ApiError::Internal(_) => "internal_error",
+        }
+    }
+
+    // Server side failures are described in the log, not to clients
+    fn public_message(&self) -> String {
+        match *self {
+            ApiError::BadRequest(ref message) |
+            ApiError::Unauthorized(ref message) |
+            ApiError::NotFound(ref message) |
+            ApiError::Unavailable(ref message) => message.clone(),
+            ApiError::Database(postgres::error::Error::Io(_)) |
+            ApiError::Connect(_) |
+            ApiError::DatabaseUnavailable(_) => "the database is unavailable".to_string(),
+            ApiError::Database(_) => "the database could not answer the request".to_string(),
+            ApiError::Config(_) |
+            ApiError::Internal(_) => "internal server error".to_string(),
+        }
+    }
+
+    pub fn response(&self, request_id: &str) -> (status::Status, String, Header<ContentType>) {
+        let body = ErrorBody {
+            code: self.code(),
+            message: self.public_message(),
+            request_id: request_id,
+        };
+
+        (self.status(), json::encode(&body).unwrap_or_default(), Header(ContentType::json()))
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ApiError::BadRequest(ref message) => write!(f, "bad request: {}", message),
+            ApiError::Unauthorized(ref message) => write!(f, "unauthorized: {}", message),
+            ApiError::NotFound(ref message) => write!(f, "not found: {}", message),
+            ApiError::Database(ref err) => write!(f, "database error: {}", err),
+            ApiError::Connect(ref err) => write!(f, "database connection error: {}", err),
+            ApiError::DatabaseUnavailable(ref message) => write!(f, "database unavailable: {}", message),
+            ApiError::Unavailable(ref message) => write!(f, "unavailable: {}", message),
+            ApiError::Config(ref message) => write!(f, "configuration error: {}", message),
+            ApiError::Internal(ref message) => write!(f, "internal error: {}", message),
+        }
+    }
+}
+
+impl Error for ApiError {
+    fn description(&self) -> &str {
+        self.code()
+    }
+}
+
+impl From<postgres::error::Error> for ApiError {
+    fn from(err: postgres::error::Error) -> ApiError {
+        ApiError::Database(err)
+    }
+}
+
+impl From<ConnectError> for ApiError {
+    fn from(err: ConnectError) -> ApiError {
+        ApiError::Connect(err)
+    }
+}
+
+impl From<r2d2::GetTimeout> for ApiError {
+    fn from(_: r2d2::GetTimeout) -> ApiError {
+        ApiError::Unavailable("all database connections are busy".to_string())
+    }
+}
+
+impl From<r2d2::InitializationError> for ApiError {
+    fn from(err: r2d2::InitializationError) -> ApiError {
+        ApiError::Unavailable(format!("could not open database connections: {}", err))
+    }
+}
+
+impl From<PersistentError> for ApiError {
+    fn from(err: PersistentError) -> ApiError {
+        ApiError::Internal(err.to_string())
+    }
+}
+
+impl<T> From<PoisonError<T>> for ApiError {
+    fn from(_: PoisonError<T>) -> ApiError {
+        ApiError::Internal("lock poisoned by a panicked request".to_string())
+    }
+}
+
+impl From<json::EncoderError> for ApiError {
+    fn from(err: json::EncoderError) -> ApiError {
+        ApiError::Internal(err.to_string())
+    }
+}
+
+// Checked replacement for Row::get, which panics on a missing column or a
+// value of the wrong type
+pub trait RowExt {
+    fn value<I, T>(&self, idx: I) -> Result<T, ApiError>
+        where I: RowIndex + fmt::Debug + Copy,
+              T: FromSql;
+}
+
+impl<'a> RowExt for Row<'a> {
+    fn value<I, T>(&self, idx: I) -> Result<T, ApiError>
+        where I: RowIndex + fmt::Debug + Copy,
+              T: FromSql
+    {
+        match self.get_opt(idx) {
+            Some(Ok(value)) => Ok(value),
+            Some(Err(err)) => Err(ApiError::Database(err)),
+            None => Err(ApiError::Internal(format!("no column {:?} in result", idx))),
+        }
+    }
+}
+
+#[derive(Copy, Clone)]
+pub struct RequestId;
+
+impl Key for RequestId { type Value = String; }
+
+pub fn request_id(req: &Request) -> String {
+    req.extensions.get::<RequestId>().cloned().unwrap_or_default()
+}
+
+// Hands out request ids, unique per process run, and echoes them back in an
+// X-Request-Id header
+pub struct RequestIds {
+    prefix: String,
+    counter: AtomicUsize,
+}
+
+impl RequestIds {
+    pub fn new() -> RequestIds {
+        RequestIds {
+            prefix: format!("{:x}", UTC::now().timestamp()),
+            counter: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl BeforeMiddleware for RequestIds {
+    fn before(&self, req: &mut Request) -> IronResult<()> {
+        let id = format!("{}-{:x}", self.prefix, self.counter.fetch_add(1, Ordering::SeqCst));
+        req.extensions.insert::<RequestId>(id);
+        Ok(())
+    }
+}
+
+impl AfterMiddleware for RequestIds {
+    fn after(&self, req: &mut Request, mut res: Response) -> IronResult<Response> {
+        res.headers.set_raw("X-Request-Id", vec![request_id(req).into_bytes()]);
+        Ok(res)
+    }
+
+    fn catch(&self, req: &mut Request, mut err: IronError) -> IronResult<Response> {
+        err.response.headers.set_raw("X-Request-Id", vec![request_id(req).into_bytes()]);
+        Err(err)
+    }
+}
+
+// Wraps a handler that returns ApiError, turning its errors, and any panic
+// that slipped through, into JSON error responses
+pub struct ApiHandler(pub fn(&mut Request) -> Result<Response, ApiError>);
+
+impl Handler for ApiHandler {
+    fn handle(&self, req: &mut Request) -> IronResult<Response> {
+        let handler = self.0;
+        let error = match panic::catch_unwind(AssertUnwindSafe(|| handler(req))) {
+            Ok(Ok(response)) => return Ok(response),
+            Ok(Err(error)) => error,
+            Err(_) => ApiError::Internal("handler panicked".to_string()),
+        };
+
+        let request_id = request_id(req);
+        if error.status().is_server_error() {
+            println!("[{}] {} {}: {}", request_id, req.method, req.url, error);
+        }
+
+        let response = error.response(&request_id);
+        Err(IronError::new(error, response))
+    }
+}
+
+// Renders errors that did not come from an ApiHandler, such as the router's
+// 404 for unknown routes, in the same JSON shape
+pub struct ErrorRenderer;
+
+impl AfterMiddleware for ErrorRenderer {
+    fn catch(&self, req: &mut Request, mut err: IronError) -> IronResult<Response> {
+        if err.error.is::<ApiError>() {
+            return Err(err);
+        }
+
+        let error = match err.response.status {
+            Some(status::NotFound) => ApiError::NotFound("no such route".to_string()),
+            Some(status) if status.is_client_error() => ApiError::BadRequest(err.error.description().to_string()),
+            Some(status) if !status.is_server_error() => return Err(err),
+            _ => ApiError::Internal(err.error.to_string()),
+        };
+
+        err.response = Response::with(error.response(&request_id(req)));
+        Err(err)
+    }
+}
@@ -0,0 +1,124 @@
+// WHERE clause builder shared by the handlers that filter products.
+//
+// Conditions and their bound parameters are collected together so the
+// placeholders ($1, $2, ...) always line up with the parameter list, no
+// matter which of the optional filters a request uses.
+
+use postgres::types::ToSql;
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum MatchMode {
+    Exact,
+    Prefix,
+    Contains,
+    Regex,
+}
+
+impl MatchMode {
+    pub fn parse(mode: &str) -> Option<MatchMode> {
+        match mode {
+            "exact" => Some(MatchMode::Exact),
+            "prefix" => Some(MatchMode::Prefix),
+            "contains" => Some(MatchMode::Contains),
+            "regex" => Some(MatchMode::Regex),
+            _ => None,
+        }
+    }
+}
+
+pub struct QueryFilter {
+    conditions: Vec<String>,
+    params: Vec<Box<ToSql>>,
+}
+
+impl QueryFilter {
+    pub fn new() -> QueryFilter {
+        QueryFilter {
+            conditions: Vec::new(),
+            params: Vec::new(),
+        }
+    }
+
+    // Binds a parameter and returns its placeholder
+    pub fn bind<T: ToSql + 'static>(&mut self, value: T) -> String {
+        self.params.push(Box::new(value));
+        format!("${}", self.params.len())
+    }
+
+    pub fn condition(&mut self, condition: String) {
+        self.conditions.push(condition);
+    }
+
+    // Matches column against any of the values, or none of them when negated.
+    // All matching is case-insensitive, like the ILIKE filters it replaces.
+    pub fn matches(&mut self, column: &str, values: &[String], mode: MatchMode, negated: bool) {
+        let condition = match mode {
+            MatchMode::Exact => {
+                let values: Vec<String> = values.iter().map(|value| value.to_lowercase()).collect();
+                format!("lower({}) = ANY({})", column, self.bind(values))
+            },
+            MatchMode::Prefix => {
+                let patterns: Vec<String> = values.iter().map(|value| format!("{}%", escape_like(value))).collect();
+                format!("{} ILIKE ANY({})", column, self.bind(patterns))
+            },
+            MatchMode::Contains => {
+                let patterns: Vec<String> = values.iter().map(|value| format!("%{}%", escape_like(value))).collect();
+                format!("{} ILIKE ANY({})", column, self.bind(patterns))
+            },
+            MatchMode::Regex => format!("{} ~* ANY({})", column, self.bind(values.to_vec())),
+        };
+
+        if negated {
+            self.condition(format!("NOT ({})", condition));
+        } else {
+            self.condition(condition);
+        }
+    }
+
+    pub fn sql(&self) -> String {
+        if self.conditions.is_empty() {
+            "TRUE".to_string()
+        } else {
+            self.conditions.join(" AND ")
+        }
+    }
+
+    pub fn params(&self) -> Vec<&ToSql> {
+        self.params.iter().map(|param| &**param).collect()
+    }
+}
+
+// Escapes the LIKE wildcards so user input only ever matches literally,
+// backslash is the default LIKE escape character in Postgres
+pub fn escape_like(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if c == '\\' || c == '%' || c == '_' {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::escape_like;
+
+    #[test]
+    fn wildcards_match_literally() {
+        assert_eq!(escape_like("100%"), "100\\%");
+        assert_eq!(escape_like("a_b"), "a\\_b");
+    }
+
+    #[test]
+    fn backslashes_are_escaped_too() {
+        assert_eq!(escape_like("a\\b"), "a\\\\b");
+        assert_eq!(escape_like("\\%"), "\\\\\\%");
+    }
+
+    #[test]
+    fn other_text_is_left_alone() {
+        assert_eq!(escape_like("BILLY bookcase"), "BILLY bookcase");
+    }
+}
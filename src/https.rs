@@ -0,0 +1,183 @@
+// HTTPS serving with certificates that can be replaced while running.
+//
+// Iron's own https() loads the certificate once, so the chain is served
+// through hyper directly with an SSL context that a watcher thread swaps out
+// whenever the certificate or key file changes on disk.
+
+use std::fs;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use hyper::net::{Fresh, HttpStream, SslServer};
+use hyper::server::{Handler as HttpHandler, Listening, Server};
+use iron::prelude::*;
+use iron::{Handler, Protocol};
+use iron::headers;
+use iron::modifiers::Header;
+use iron::request::HttpRequest;
+use iron::response::HttpResponse;
+use iron::status;
+use openssl::ssl::{SslContext, SslMethod, SslStream, SSL_VERIFY_NONE, SSL_OP_NO_SSLV2, SSL_OP_NO_SSLV3,
+                   SSL_OP_NO_COMPRESSION};
+use openssl::ssl::error::StreamError;
+use openssl::x509::X509FileType;
+
+use error::ApiError;
+
+#[derive(Clone)]
+pub struct ReloadableTls {
+    certificate: PathBuf,
+    key: PathBuf,
+    context: Arc<RwLock<Arc<SslContext>>>,
+}
+
+impl ReloadableTls {
+    pub fn new(certificate: &str, key: &str) -> Result<ReloadableTls, ApiError> {
+        let certificate = PathBuf::from(certificate);
+        let key = PathBuf::from(key);
+        let context = try!(load_context(&certificate, &key));
+
+        Ok(ReloadableTls {
+            certificate: certificate,
+            key: key,
+            context: Arc::new(RwLock::new(Arc::new(context))),
+        })
+    }
+
+    // Connections already open keep the context they were accepted with,
+    // new ones get the reloaded certificate. A certificate that fails to
+    // load leaves the current one in place.
+    pub fn reload(&self) -> Result<(), ApiError> {
+        let context = try!(load_context(&self.certificate, &self.key));
+        *try!(self.context.write()) = Arc::new(context);
+        Ok(())
+    }
+
+    // Polls the files for changes. Renewals that write the certificate and
+    // the key one after the other can fail to load in between, the second
+    // write then triggers another reload.
+    pub fn watch(&self, interval: Duration) {
+        let tls = self.clone();
+        thread::spawn(move || {
+            let mut modified = tls.modified();
+            loop {
+                thread::sleep(interval);
+                let current = tls.modified();
+                if current == modified {
+                    continue;
+                }
+                modified = current;
+
+                match tls.reload() {
+                    Ok(()) => println!("Reloaded TLS certificate {}", tls.certificate.display()),
+                    Err(error) => println!("Keeping the current TLS certificate: {}", error),
+                }
+            }
+        });
+    }
+
+    fn modified(&self) -> (Option<SystemTime>, Option<SystemTime>) {
+        let modified = |path: &PathBuf| fs::metadata(path).and_then(|metadata| metadata.modified()).ok();
+        (modified(&self.certificate), modified(&self.key))
+    }
+
+    fn current(&self) -> Arc<SslContext> {
+        match self.context.read() {
+            Ok(context) => context.clone(),
+            Err(poisoned) => poisoned.into_inner().clone(),
+        }
+    }
+}
+
+impl SslServer for ReloadableTls {
+    type Stream = SslStream<HttpStream>;
+
+    fn wrap_server(&self, stream: HttpStream) -> ::hyper::Result<SslStream<HttpStream>> {
+        match SslStream::accept(&*self.current(), stream) {
+            Ok(stream) => Ok(stream),
+            Err(StreamError(e)) => Err(io::Error::new(io::ErrorKind::ConnectionAborted, e).into()),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+fn load_context(certificate: &PathBuf, key: &PathBuf) -> Result<SslContext, ApiError> {
+    let config_error = |path: &PathBuf, err: &::std::error::Error| ApiError::Config(format!("{}: {}", path.display(), err));
+
+    let mut context = try!(SslContext::new(SslMethod::Sslv23).map_err(|e| ApiError::Internal(e.to_string())));
+    // Sslv23 negotiates the best version both ends speak, down to the broken
+    // SSL ones unless they are ruled out. Compression leaks secrets (CRIME).
+    context.set_options(SSL_OP_NO_SSLV2 | SSL_OP_NO_SSLV3 | SSL_OP_NO_COMPRESSION);
+    try!(context.set_cipher_list("DEFAULT").map_err(|e| ApiError::Internal(e.to_string())));
+    try!(context.set_certificate_chain_file(certificate, X509FileType::PEM).map_err(|e| config_error(certificate, &e)));
+    try!(context.set_private_key_file(key, X509FileType::PEM).map_err(|e| config_error(key, &e)));
+    try!(context.check_private_key().map_err(|e| config_error(key, &e)));
+    context.set_verify(SSL_VERIFY_NONE, None);
+
+    Ok(context)
+}
+
+// Does for hyper what Iron::listen_with does, which is private to Iron
+struct ServeChain<H> {
+    handler: H,
+    addr: SocketAddr,
+    protocol: Protocol,
+}
+
+impl<H: Handler> HttpHandler for ServeChain<H> {
+    fn handle(&self, http_req: HttpRequest, mut http_res: HttpResponse<Fresh>) {
+        *http_res.status_mut() = status::InternalServerError;
+
+        match Request::from_http(http_req, self.addr, &self.protocol) {
+            Ok(mut req) => {
+                self.handler.handle(&mut req)
+                    .unwrap_or_else(|e| e.response)
+                    .write_back(http_res)
+            },
+            Err(_) => {
+                *http_res.status_mut() = status::BadRequest;
+                if let Ok(res) = http_res.start() {
+                    let _ = res.end();
+                }
+            },
+        }
+    }
+}
+
+pub fn serve<H: Handler>(handler: H, address: &str, tls: ReloadableTls) -> Result<Listening, ApiError> {
+    let addr = match address.to_socket_addrs().ok().and_then(|mut addrs| addrs.next()) {
+        Some(addr) => addr,
+        None => return Err(ApiError::Config(format!("invalid address {}", address))),
+    };
+
+    let protocol = Protocol::Https {
+        certificate: tls.certificate.clone(),
+        key: tls.key.clone(),
+    };
+
+    let server = try!(Server::https(addr, tls)
+        .map_err(|e| ApiError::Internal(format!("cannot listen on {}: {}", address, e))));
+
+    server.handle(ServeChain { handler: handler, addr: addr, protocol: protocol })
+        .map_err(|e| ApiError::Internal(format!("cannot listen on {}: {}", address, e)))
+}
+
+// Answers plain HTTP requests with a permanent redirect to the same URL on
+// the HTTPS port
+pub struct RedirectToHttps {
+    pub port: u16,
+}
+
+impl Handler for RedirectToHttps {
+    fn handle(&self, req: &mut Request) -> IronResult<Response> {
+        let mut url = req.url.clone().into_generic_url();
+        let _ = url.set_scheme("https");
+        let _ = url.set_port(if self.port == 443 { None } else { Some(self.port) });
+
+        Ok(Response::with((status::MovedPermanently, Header(headers::Location(url.to_string())))))
+    }
+}
@@ -1,15 +1,43 @@
 extern crate iron;
+extern crate hyper;
 extern crate router;
 extern crate persistent;
 extern crate urlencoded;
 extern crate postgres;
+extern crate r2d2;
+extern crate r2d2_postgres;
 extern crate chrono;
 extern crate rustc_serialize;
 extern crate url;
 extern crate getopts;
+extern crate toml;
+extern crate openssl;
+extern crate openssl_verify;
+extern crate flate2;
+extern crate brotli2;
+
+mod cache;
+mod compression;
+mod conditional;
+mod config;
+mod cors;
+mod db;
+mod error;
+mod filter;
+mod https;
+mod price;
+mod suggest;
+mod tls;
 
 // Std
+use std::cmp::{self, Ordering};
+use std::collections::{BTreeMap, HashMap};
 use std::env;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read};
+use std::process;
+use std::thread;
+use std::time::{Duration, Instant};
 
 // Iron
 use iron::prelude::*;
@@ -21,13 +49,15 @@ use iron::typemap::Key;
 use router::Router;
 
 // Persistent
-use persistent::Write;
+use persistent::{Read as PersistentRead, State};
 
 // Urlencoded
 use urlencoded::UrlEncodedQuery;
 
 // Postgres
-use postgres::{Connection, SslMode};
+use postgres::Connection;
+use postgres::rows::Row;
+use postgres::types::ToSql;
 
 // URL
 use url::percent_encoding::*;
@@ -36,488 +66,1878 @@ use url::percent_encoding::*;
 use chrono::*;
 
 // JSON
+use rustc_serialize::Encodable;
 use rustc_serialize::json;
+use rustc_serialize::json::Json;
+use rustc_serialize::base64::{FromBase64, ToBase64, URL_SAFE};
 
 // Getopts
 use getopts::Options;
 
+// Cache
+use cache::{Cache, Cached, ResponseCache};
+
+// Compression
+use compression::Compression;
+
+// Conditional GET
+use conditional::{ConditionalGet, etag, set_last_modified};
+
+// Config
+use config::Config;
+
+// CORS
+use cors::{Cors, CorsOptions};
+
+// Database
+use db::{DatabasePool, Pool, PoolOptions, connect_pool};
+
+// Error
+use error::{ApiError, ApiHandler, ErrorRenderer, RequestIds, RowExt};
+
+// HTTPS
+use https::{RedirectToHttps, ReloadableTls};
+
+// Filter
+use filter::{MatchMode, QueryFilter};
+
+// Price
+use price::{currency_for_country, parse_price};
+
+// Suggest
+use suggest::{Signature, SuggestIndex};
+
+// TLS
+use tls::{TlsMode, TlsOptions};
+
+// Columns read by product_from_row, in order
+const PRODUCT_COLUMNS: &'static str =
+    "id, name, typ, country, price, unit, metric, url, image_url,
+     department, category, subcategory, department_url, category_url, subcategory_url,
+     created_at, updated_at, price_amount::float8 AS price_amount, currency";
+
+// Fields that can be picked with the fields parameter, with the column each
+// one is read from. Without the parameter responses carry all of them.
+const PRODUCT_FIELDS: &'static [ProductField] = &[
+    ProductField { name: "id", column: "id", kind: FieldKind::Text },
+    ProductField { name: "name", column: "name", kind: FieldKind::Text },
+    ProductField { name: "typ", column: "typ", kind: FieldKind::Text },
+    ProductField { name: "country", column: "country", kind: FieldKind::Text },
+    ProductField { name: "unit", column: "unit", kind: FieldKind::Text },
+    ProductField { name: "price", column: "price", kind: FieldKind::Text },
+    ProductField { name: "price_amount", column: "price_amount::float8", kind: FieldKind::Number },
+    ProductField { name: "currency", column: "currency", kind: FieldKind::OptionalText },
+    ProductField { name: "metric", column: "metric", kind: FieldKind::Text },
+    ProductField { name: "image_url", column: "image_url", kind: FieldKind::Text },
+    ProductField { name: "url", column: "url", kind: FieldKind::Text },
+    ProductField { name: "department", column: "department", kind: FieldKind::Text },
+    ProductField { name: "category", column: "category", kind: FieldKind::Text },
+    ProductField { name: "subcategory", column: "subcategory", kind: FieldKind::Text },
+    ProductField { name: "department_url", column: "department_url", kind: FieldKind::Text },
+    ProductField { name: "category_url", column: "category_url", kind: FieldKind::Text },
+    ProductField { name: "subcategory_url", column: "subcategory_url", kind: FieldKind::Text },
+    ProductField { name: "created_at", column: "created_at", kind: FieldKind::Timestamp },
+    ProductField { name: "updated_at", column: "updated_at", kind: FieldKind::Timestamp },
+];
+
+const DEFAULT_PAGE_LIMIT: i64 = 100;
+const MAX_PAGE_LIMIT: i64 = 1000;
+
+// Facets that /products can count, see the facets parameter
+const FACETS: &'static [&'static str] = &["department", "category", "subcategory", "type", "country", "price"];
+const PRICE_BUCKETS: usize = 10;
+
+const MAX_BODY_BYTES: u64 = 1024 * 1024;
+const MAX_BASKET_ITEMS: usize = 500;
+
+const DEFAULT_SUGGEST_LIMIT: usize = 10;
+const MAX_SUGGEST_LIMIT: usize = 50;
+
+// How often /suggest checks whether the product table has changed
+const SUGGEST_REFRESH_SECS: u64 = 30;
+
+// How often the product table is checked for a crawl with new prices
+const PRICE_CHECK_SECS: u64 = 30;
+
+// How often the HTTPS certificate and key files are checked for changes
+const CERTIFICATE_CHECK_SECS: u64 = 10;
+
+// Postgres text search configuration to use for each market
+const SEARCH_CONFIGS: &'static [(&'static str, &'static str)] = &[
+    ("at", "german"),
+    ("au", "english"),
+    ("be", "dutch"),
+    ("ca", "english"),
+    ("ch", "german"),
+    ("de", "german"),
+    ("dk", "danish"),
+    ("es", "spanish"),
+    ("fi", "finnish"),
+    ("fr", "french"),
+    ("gb", "english"),
+    ("hu", "hungarian"),
+    ("ie", "english"),
+    ("it", "italian"),
+    ("my", "english"),
+    ("nl", "dutch"),
+    ("no", "norwegian"),
+    ("pt", "portuguese"),
+    ("ro", "romanian"),
+    ("ru", "russian"),
+    ("se", "swedish"),
+    ("sg", "english"),
+    ("tr", "turkish"),
+    ("uk", "english"),
+    ("us", "english"),
+];
+
+#[derive(Copy, Clone)]
+pub struct SuggestCache;
+
+impl Key for SuggestCache { type Value = SuggestIndex; }
+
 #[derive(Copy, Clone)]
-pub struct DatabaseConnection;
+pub struct AdminToken;
+
+impl Key for AdminToken { type Value = String; }
+
+#[derive(RustcEncodable)]
+struct CachePurge {
+    purged: usize,
+}
+
+#[derive(RustcEncodable)]
+struct Product {
+    id: String,
+    name: String,
+    typ: String,
+    country: String,
+    unit: String,
+    price: String,
+    price_amount: Option<f64>,
+    currency: Option<String>,
+    metric: String,
+    image_url: String,
+    url: String,
+	department: String,
+	category: String,
+	subcategory: String,
+	department_url: String,
+	category_url: String,
+	subcategory_url: String,
+	created_at: String,
+	updated_at: String,
+}
+
+enum FieldKind {
+    Text,
+    OptionalText,
+    Number,
+    Timestamp,
+}
+
+struct ProductField {
+    name: &'static str,
+    column: &'static str,
+    kind: FieldKind,
+}
+
+#[derive(RustcEncodable)]
+struct ProductPage {
+    products: Vec<Json>,
+    total: i64,
+    next_cursor: Option<String>,
+    facets: Option<Facets>,
+}
+
+#[derive(RustcEncodable)]
+struct FacetCount {
+    value: String,
+    products: i64,
+}
+
+#[derive(RustcEncodable)]
+struct PriceBucket {
+    min: f64,
+    max: f64,
+    products: i64,
+}
+
+// Only the facets asked for are filled in
+#[derive(RustcEncodable)]
+struct Facets {
+    department: Option<Vec<FacetCount>>,
+    category: Option<Vec<FacetCount>>,
+    subcategory: Option<Vec<FacetCount>>,
+    typ: Option<Vec<FacetCount>>,
+    country: Option<Vec<FacetCount>>,
+    price: Option<Vec<PriceBucket>>,
+}
+
+struct SortOrder {
+    name: &'static str,
+    expression: &'static str,
+    sql_type: &'static str,
+    descending: bool,
+}
+
+#[derive(RustcEncodable)]
+struct Market {
+    country: String,
+    products: Vec<Json>,
+    currency: Option<String>,
+    // Sum of price times quantity, only known when every product is priced
+    total: Option<f64>,
+    converted_total: Option<f64>,
+    // Requested ids this market does not sell
+    missing: Vec<String>,
+}
+
+#[derive(RustcDecodable)]
+struct BasketItem {
+    id: String,
+    quantity: i64,
+}
+
+#[derive(RustcDecodable)]
+struct Basket {
+    items: Vec<BasketItem>,
+    currency: Option<String>,
+}
+
+#[derive(RustcEncodable)]
+struct BasketComparison {
+    currency: Option<String>,
+    // Cheapest market that sells every item in the basket
+    cheapest_market: Option<String>,
+    markets: Vec<Market>,
+}
+
+#[derive(RustcEncodable)]
+struct ComparedPrice {
+    product: Product,
+    converted_amount: Option<f64>,
+    // Percentage above (positive) or below (negative) the median
+    difference_from_median: Option<f64>,
+}
+
+#[derive(RustcEncodable)]
+struct Comparison {
+    id: String,
+    currency: String,
+    median: Option<f64>,
+    prices: Vec<ComparedPrice>,
+}
+
+#[derive(RustcEncodable)]
+struct TypeCount {
+    typ: String,
+    products: i64,
+}
+
+#[derive(RustcEncodable)]
+struct TaxonomyEntry {
+    name: String,
+    slug: String,
+    url: String,
+    products: i64,
+}
+
+#[derive(RustcEncodable)]
+struct TaxonomyNode {
+    name: String,
+    slug: String,
+    url: String,
+    products: i64,
+    // Price range, only given when the products share one currency
+    min_price: Option<f64>,
+    max_price: Option<f64>,
+    currency: Option<String>,
+    children: Vec<TaxonomyNode>,
+}
+
+#[derive(RustcEncodable)]
+struct PricePoint {
+    price: Option<String>,
+    price_amount: Option<f64>,
+    recorded_at: String,
+}
+
+#[derive(RustcEncodable)]
+struct PriceHistory {
+    country: String,
+    currency: Option<String>,
+    min: Option<f64>,
+    max: Option<f64>,
+    current: Option<f64>,
+    points: Vec<PricePoint>,
+}
+
+#[derive(RustcEncodable)]
+struct PriceError {
+    id: String,
+    country: String,
+    price: String,
+    error: String,
+}
+
+#[derive(RustcEncodable)]
+struct SearchResult {
+    product: Product,
+    rank: f32,
+    snippet: String,
+}
+
+fn product_from_row(row: &Row) -> Result<Product, ApiError> {
+    let created_at: DateTime<UTC> = try!(row.value(15));
+    let updated_at: DateTime<UTC> = try!(row.value(16));
+
+    Ok(Product {
+        id: try!(row.value(0)),
+        name: try!(row.value(1)),
+        typ: try!(row.value(2)),
+        country: try!(row.value(3)),
+        price: try!(row.value(4)),
+        price_amount: try!(row.value(17)),
+        currency: try!(row.value(18)),
+        unit: try!(row.value(5)),
+        metric: try!(row.value(6)),
+        url: try!(row.value(7)),
+        image_url: try!(row.value(8)),
+        department: try!(row.value(9)),
+        category: try!(row.value(10)),
+        subcategory: try!(row.value(11)),
+        department_url: try!(row.value(12)),
+        category_url: try!(row.value(13)),
+        subcategory_url: try!(row.value(14)),
+        created_at: created_at.to_rfc2822(),
+        updated_at: updated_at.to_rfc2822(),
+    })
+}
+
+// Reads the fields parameter, a comma separated list of PRODUCT_FIELDS names
+fn requested_fields(req: &mut Request) -> Result<Vec<&'static ProductField>, String> {
+    let names: Vec<String> = match req.get_ref::<UrlEncodedQuery>() {
+        Ok(ref hashmap) => {
+            match hashmap.get("fields") {
+                Some(fields) => fields.iter()
+                    .flat_map(|fields| fields.split(','))
+                    .map(|field| field.trim().to_string())
+                    .filter(|field| !field.is_empty())
+                    .collect(),
+                None => Vec::new(),
+            }
+        },
+        Err(_) => Vec::new(),
+    };
+
+    if names.is_empty() {
+        return Ok(PRODUCT_FIELDS.iter().collect());
+    }
+
+    let mut fields = Vec::new();
+    for name in &names {
+        match PRODUCT_FIELDS.iter().find(|field| field.name == *name) {
+            Some(field) => fields.push(field),
+            None => return Err(format!("unknown field {}", name)),
+        }
+    }
+    Ok(fields)
+}
+
+fn field_columns(fields: &[&ProductField]) -> String {
+    let columns: Vec<&str> = fields.iter().map(|field| field.column).collect();
+    columns.join(", ")
+}
+
+// Builds the JSON object for a row whose first columns are fields
+fn product_json(row: &Row, fields: &[&ProductField]) -> Result<Json, ApiError> {
+    let mut object = BTreeMap::new();
+
+    for (index, field) in fields.iter().enumerate() {
+        let value = match field.kind {
+            FieldKind::Text => Json::String(try!(row.value(index))),
+            FieldKind::OptionalText => try!(row.value::<_, Option<String>>(index)).map_or(Json::Null, Json::String),
+            FieldKind::Number => try!(row.value::<_, Option<f64>>(index)).map_or(Json::Null, Json::F64),
+            FieldKind::Timestamp => Json::String(try!(row.value::<_, DateTime<UTC>>(index)).to_rfc2822()),
+        };
+        object.insert(field.name.to_string(), value);
+    }
+
+    Ok(Json::Object(object))
+}
+
+// Adds the columns this server maintains on top of the spider's product table
+fn setup_database(conn: &Connection) -> postgres::Result<()> {
+    try!(conn.batch_execute(
+        "ALTER TABLE product
+             ADD COLUMN IF NOT EXISTS price_amount numeric(14, 2),
+             ADD COLUMN IF NOT EXISTS currency text,
+             ADD COLUMN IF NOT EXISTS price_source text,
+             ADD COLUMN IF NOT EXISTS price_error text;
+
+         CREATE TABLE IF NOT EXISTS exchange_rate (
+             currency text PRIMARY KEY,
+             rate numeric(20, 8) NOT NULL CHECK (rate > 0),
+             updated_at timestamptz NOT NULL DEFAULT now()
+         );
+
+         CREATE OR REPLACE FUNCTION taxonomy_slug(name text) RETURNS text AS $$
+             SELECT trim(both '-' from lower(regexp_replace(name, '[^[:alnum:]]+', '-', 'g')))
+         $$ LANGUAGE sql IMMUTABLE;
+
+         CREATE TABLE IF NOT EXISTS price_history (
+             id text NOT NULL,
+             country text NOT NULL,
+             price text,
+             price_amount numeric(14, 2),
+             currency text,
+             recorded_at timestamptz NOT NULL,
+             PRIMARY KEY (id, country, recorded_at)
+         );"));
+
+    // Changing SEARCH_CONFIGS changes the expression, the index then has to
+    // be dropped so that it is built again
+    conn.batch_execute(&format!(
+        "CREATE INDEX IF NOT EXISTS product_search_idx ON product USING gin (({}))",
+        search_document_sql()))
+}
+
+// Appends the current price of every product whose price differs from the
+// last one recorded for it. Products without any history get their first
+// entry, so running the server with --parse-prices once backfills the table
+// for data imported before the history existed. Afterwards the server records
+// changes at startup and whenever it notices a crawl. Rows the crawler
+// updated after their price was parsed wait for the next round, their amount
+// still belongs to the old price.
+fn record_price_history(conn: &Connection) -> postgres::Result<u64> {
+    conn.execute(
+        "INSERT INTO price_history (id, country, price, price_amount, currency, recorded_at)
+         SELECT product.id, product.country, product.price, product.price_amount, product.currency, product.updated_at
+         FROM product
+         WHERE product.price_source IS NOT DISTINCT FROM product.price
+         AND product.price IS DISTINCT FROM (
+             SELECT history.price FROM price_history AS history
+             WHERE history.id = product.id AND history.country = product.country
+             ORDER BY history.recorded_at DESC
+             LIMIT 1
+         )
+         ON CONFLICT DO NOTHING",
+        &[])
+}
+
+// Imports exchange rates from a file of "currency,rate" lines where rate is
+// the number of units of the currency that one US dollar buys. Blank lines
+// and lines starting with '#' are skipped. Nothing is imported unless every
+// line is valid.
+fn import_exchange_rates(conn: &Connection, path: &str) -> Result<usize, String> {
+    let file = try!(File::open(path).map_err(|e| format!("{}: {}", path, e)));
+
+    let mut rates = vec![("USD".to_string(), 1.0)];
+    for (number, line) in BufReader::new(file).lines().enumerate() {
+        let line = try!(line.map_err(|e| format!("{}: {}", path, e)));
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') || line.to_lowercase() == "currency,rate" {
+            continue;
+        }
+
+        let fields: Vec<&str> = line.split(',').map(|field| field.trim()).collect();
+        if fields.len() != 2 || fields[0].len() != 3 || !fields[0].chars().all(|c| c.is_alphabetic()) {
+            return Err(format!("{}:{}: expected \"currency,rate\"", path, number + 1));
+        }
+
+        match fields[1].parse::<f64>() {
+            Ok(rate) if rate > 0.0 && rate.is_finite() => rates.push((fields[0].to_uppercase(), rate)),
+            _ => return Err(format!("{}:{}: invalid rate {:?}", path, number + 1, fields[1])),
+        }
+    }
+
+    let trans = try!(conn.transaction().map_err(|e| e.to_string()));
+    for &(ref currency, rate) in &rates {
+        try!(trans.execute(
+            "INSERT INTO exchange_rate (currency, rate, updated_at) VALUES ($1, CAST($2::float8 AS numeric), now())
+             ON CONFLICT (currency) DO UPDATE SET rate = EXCLUDED.rate, updated_at = EXCLUDED.updated_at",
+            &[currency, &rate]).map_err(|e| e.to_string()));
+    }
+    try!(trans.commit().map_err(|e| e.to_string()));
+
+    Ok(rates.len())
+}
+
+fn exchange_rates(conn: &Connection) -> Result<HashMap<String, f64>, ApiError> {
+    let mut rates = HashMap::new();
+    for row in &try!(conn.query("SELECT currency, rate::float8 FROM exchange_rate", &[])) {
+        rates.insert(try!(row.value(0)), try!(row.value(1)));
+    }
+    Ok(rates)
+}
+
+// Groups the products with the given ids by country and prices each
+// market's basket, quantities of repeated ids add up
+fn markets_for_items(conn: &Connection, items: &[(String, i64)], fields: &[&ProductField]) -> Result<(Vec<Market>, Option<DateTime<UTC>>), ApiError> {
+    let mut ids: Vec<String> = Vec::new();
+    let mut quantities: HashMap<String, i64> = HashMap::new();
+    for &(ref id, quantity) in items {
+        if !quantities.contains_key(id) {
+            ids.push(id.clone());
+        }
+        *quantities.entry(id.clone()).or_insert(0) += quantity;
+    }
+
+    let mut markets: Vec<Market> = Vec::new();
+    let mut found: Vec<Vec<String>> = Vec::new();
+    let mut last_modified: Option<DateTime<UTC>> = None;
+
+    for row in &try!(conn.query(&format!("SELECT {}, id AS market_id, country AS market_country,
+                                                 price_amount::float8 AS market_amount, currency AS market_currency,
+                                                 updated_at AS market_updated_at
+                                          FROM product WHERE id = ANY($1) ORDER BY country, id",
+                                         field_columns(fields)),
+                                &[&ids])) {
+        let id: String = try!(row.value("market_id"));
+        let country: String = try!(row.value("market_country"));
+        let amount: Option<f64> = try!(row.value("market_amount"));
+        last_modified = cmp::max(last_modified, Some(try!(row.value("market_updated_at"))));
+
+        if markets.last().map_or(true, |market| market.country != country) {
+            markets.push(Market {
+                country: country,
+                products: Vec::new(),
+                currency: try!(row.value("market_currency")),
+                total: Some(0.0),
+                converted_total: None,
+                missing: Vec::new(),
+            });
+            found.push(Vec::new());
+        }
+
+        let market = markets.last_mut().unwrap();
+        market.total = match (market.total, amount) {
+            (Some(total), Some(amount)) => Some(round_cents(total + amount * quantities[&id] as f64)),
+            _ => None,
+        };
+        market.products.push(try!(product_json(&row, fields)));
+        found.last_mut().unwrap().push(id);
+    }
+
+    for (market, found) in markets.iter_mut().zip(found.iter()) {
+        market.missing = ids.iter()
+            .filter(|id| !found.contains(id))
+            .cloned()
+            .collect();
+    }
+
+    Ok((markets, last_modified))
+}
+
+fn convert(amount: f64, from: &str, to: &str, rates: &HashMap<String, f64>) -> Option<f64> {
+    match (rates.get(from), rates.get(to)) {
+        (Some(from), Some(to)) => Some(round_cents(amount / from * to)),
+        _ => None,
+    }
+}
+
+fn round_cents(amount: f64) -> f64 {
+    (amount * 100.0).round() / 100.0
+}
+
+fn median(values: &[f64]) -> Option<f64> {
+    let mut sorted = values.to_vec();
+    sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
+
+    match sorted.len() {
+        0 => None,
+        n if n % 2 == 1 => Some(sorted[n / 2]),
+        n => Some((sorted[n / 2 - 1] + sorted[n / 2]) / 2.0),
+    }
+}
+
+// Parses and records the prices of crawls that run while the server is up.
+// Crawls are noticed the way /suggest notices them, by the row count and the
+// newest updated_at of the product table.
+fn watch_prices(pool: Pool) {
+    thread::spawn(move || {
+        let mut signature = None;
+        loop {
+            thread::sleep(Duration::from_secs(PRICE_CHECK_SECS));
+            if let Err(error) = parse_changed_prices(&pool, &mut signature) {
+                println!("Parsing changed prices failed: {}", error);
+            }
+        }
+    });
+}
+
+fn parse_changed_prices(pool: &Pool, signature: &mut Option<Signature>) -> Result<(), ApiError> {
+    let conn = try!(pool.get());
+
+    let rows = try!(conn.query("SELECT count(*), max(updated_at) FROM product", &[]));
+    let row = rows.get(0);
+    let current = Signature { rows: try!(row.value(0)), updated_at: try!(row.value(1)) };
+    if signature.as_ref() == Some(&current) {
+        return Ok(());
+    }
+
+    let (parsed, errors) = try!(parse_prices(&conn));
+    if parsed > 0 || !errors.is_empty() {
+        println!("Parsed {} changed prices, {} failed", parsed, errors.len());
+    }
+
+    let recorded = try!(record_price_history(&conn));
+    if recorded > 0 {
+        println!("Recorded {} price changes", recorded);
+    }
+
+    *signature = Some(current);
+    Ok(())
+}
+
+// Parses the price of every row whose price string changed since it was last
+// parsed. Rows that fail keep a NULL amount and record why in price_error.
+fn parse_prices(conn: &Connection) -> Result<(u64, Vec<PriceError>), ApiError> {
+    let trans = try!(conn.transaction());
+    let update = try!(trans.prepare(
+        "UPDATE product
+         SET price_amount = $1::text::numeric, currency = $2, price_source = $3, price_error = $4
+         WHERE id = $5 AND country = $6"));
+
+    let mut parsed = 0;
+    let mut errors = Vec::new();
+
+    for row in &try!(trans.query("SELECT id, country, price FROM product WHERE price_source IS DISTINCT FROM price", &[])) {
+        let id: String = try!(row.value(0));
+        let country: String = try!(row.value(1));
+        let price: String = try!(row.value(2));
+
+        match parse_price(&price, &country) {
+            Ok(parsed_price) => {
+                try!(update.execute(&[&Some(parsed_price.amount()), &parsed_price.currency, &price, &None::<String>, &id, &country]));
+                parsed += 1;
+            },
+            Err(error) => {
+                try!(update.execute(&[&None::<String>, &currency_for_country(&country), &price, &error, &id, &country]));
+                errors.push(PriceError { id: id, country: country, price: price, error: error });
+            },
+        }
+    }
+
+    try!(update.finish());
+    try!(trans.commit());
+
+    Ok((parsed, errors))
+}
+
+fn facet_counts(conn: &Connection, filter: &QueryFilter, column: &str) -> Result<Vec<FacetCount>, ApiError> {
+    let mut counts = Vec::new();
+    for row in &try!(conn.query(&format!("SELECT {column}, count(*) FROM product WHERE {} GROUP BY {column} ORDER BY count(*) DESC, {column} ASC",
+                                         filter.sql(), column = column),
+                                &filter.params())) {
+        counts.push(FacetCount {
+            value: try!(row.value(0)),
+            products: try!(row.value(1)),
+        });
+    }
+    Ok(counts)
+}
+
+// Splits the price range into buckets of a round width (1, 2 or 5 times a
+// power of ten) so that there are at most PRICE_BUCKETS of them. Amounts in
+// different currencies do not compare, so there are none unless the matching
+// prices share one, as with the price range of /taxonomy.
+fn price_buckets(conn: &Connection, filter: &QueryFilter) -> Result<Vec<PriceBucket>, ApiError> {
+    let rows = try!(conn.query(&format!("SELECT min(price_amount)::float8, max(price_amount)::float8, count(DISTINCT currency)
+                                         FROM product
+                                         WHERE {} AND price_amount IS NOT NULL",
+                                        filter.sql()),
+                               &filter.params()));
+    let row = rows.get(0);
+    let (min, max): (Option<f64>, Option<f64>) = (try!(row.value(0)), try!(row.value(1)));
+    let currencies: i64 = try!(row.value(2));
+    let (min, max) = match (min, max) {
+        (Some(min), Some(max)) if currencies == 1 => (min, max),
+        _ => return Ok(Vec::new()),
+    };
+
+    let rough = ((max - min) / PRICE_BUCKETS as f64).max(1.0);
+    let magnitude = 10f64.powf(rough.log10().floor());
+    let width = [1.0, 2.0, 5.0, 10.0].iter()
+        .map(|step| step * magnitude)
+        .find(|&width| width >= rough)
+        .unwrap();
+
+    let mut buckets = Vec::new();
+    for row in &try!(conn.query(&format!("SELECT floor(price_amount::float8 / {width}) * {width} AS bucket, count(*)
+                                          FROM product
+                                          WHERE {} AND price_amount IS NOT NULL
+                                          GROUP BY bucket ORDER BY bucket ASC",
+                                         filter.sql(), width = width),
+                                &filter.params())) {
+        let bucket_min: f64 = try!(row.value(0));
+        buckets.push(PriceBucket {
+            min: bucket_min,
+            max: bucket_min + width,
+            products: try!(row.value(1)),
+        });
+    }
+    Ok(buckets)
+}
+
+// Orders accepted by the sort parameter of /products. Prices that failed to
+// parse sort last in both directions.
+fn sort_order(sort: &str) -> Option<SortOrder> {
+    let (name, expression, sql_type, descending) = match sort {
+        "name" => ("name", "name", "text", false),
+        "-name" => ("-name", "name", "text", true),
+        "price" => ("price", "coalesce(price_amount::float8, 'Infinity')", "float8", false),
+        "-price" => ("-price", "coalesce(price_amount::float8, '-Infinity')", "float8", true),
+        "updated_at" => ("updated_at", "updated_at", "timestamptz", false),
+        "-updated_at" => ("-updated_at", "updated_at", "timestamptz", true),
+        _ => return None,
+    };
+
+    Some(SortOrder {
+        name: name,
+        expression: expression,
+        sql_type: sql_type,
+        descending: descending,
+    })
+}
+
+// Cursors are the sort, sort key and id of the last product on a page, JSON
+// encoded and then base64 encoded so that clients treat them as opaque strings
+fn encode_cursor(sort: &str, key: &str, id: &str) -> String {
+    json::encode(&(sort, key, id)).unwrap().as_bytes().to_base64(URL_SAFE)
+}
+
+fn decode_cursor(cursor: &str) -> Option<(String, String, String)> {
+    let bytes = match cursor.from_base64() {
+        Ok(bytes) => bytes,
+        Err(_) => return None,
+    };
+
+    match String::from_utf8(bytes) {
+        Ok(text) => json::decode(&text).ok(),
+        Err(_) => None,
+    }
+}
+
+// Builds a SQL expression that picks the text search configuration for a
+// product from its country column, falling back to the 'simple' config. The
+// configs are regconfig constants, which keeps the expression immutable so
+// that it can be indexed.
+fn search_config_sql(column: &str) -> String {
+    let mut sql = format!("CASE lower({})", column);
+    for &(country, config) in SEARCH_CONFIGS {
+        sql.push_str(&format!(" WHEN '{}' THEN '{}'::regconfig", country, config));
+    }
+    sql.push_str(" ELSE 'simple'::regconfig END");
+    sql
+}
+
+// The query parsed with every config. The index cannot be used with the
+// per-row config of the exact match, this looser match narrows the rows down
+// through the index first.
+fn search_any_query_sql(q: &str) -> String {
+    let mut configs: Vec<&str> = SEARCH_CONFIGS.iter().map(|&(_, config)| config).collect();
+    configs.push("simple");
+    configs.sort();
+    configs.dedup();
+
+    let queries: Vec<String> = configs.iter()
+        .map(|config| format!("plainto_tsquery('{}', {})", config, q))
+        .collect();
+    format!("({})", queries.join(" || "))
+}
+
+// The weighted document /search matches against. It has to stay the same
+// expression as product_search_idx for the index to be used.
+fn search_document_sql() -> String {
+    let config = search_config_sql("country");
+    format!("setweight(to_tsvector({config}, coalesce(name, '')), 'A') ||
+             setweight(to_tsvector({config}, coalesce(typ, '')), 'B') ||
+             setweight(to_tsvector({config}, coalesce(department, '') || ' ' || coalesce(category, '') || ' ' || coalesce(subcategory, '')), 'C')",
+            config = config)
+}
+
+// Adds conditions on column for the query parameter param, when the request
+// has it. Repeated parameters match any of their values, and param! (as in
+// department!=Kitchen) excludes values. Values are matched exactly unless
+// <param>_match asks for prefix, contains or regex. Except in regex mode, where
+// commas belong to the expression ("^[a-z]{2,3}$"), a parameter can also list
+// values separated by commas, with "\," for a comma inside a value.
+fn match_param(req: &mut Request, conn: &Connection, filter: &mut QueryFilter, param: &str, column: &str) -> Result<(), String> {
+    let (included, excluded, mode) = match req.get_ref::<UrlEncodedQuery>() {
+        Ok(ref hashmap) => {
+            let mode = match hashmap.get(&format!("{}_match", param)) {
+                Some(mode) => match MatchMode::parse(&mode[0]) {
+                    Some(mode) => mode,
+                    None => return Err(format!("invalid {}_match, expected exact, prefix, contains or regex", param)),
+                },
+                None => MatchMode::Exact,
+            };
+
+            let values = |key: &str| -> Vec<String> {
+                hashmap.get(key).map_or(Vec::new(), |values| {
+                    values.iter()
+                        .flat_map(|value| if mode == MatchMode::Regex { vec![value.clone()] } else { split_list(value) })
+                        .map(|value| value.trim().to_string())
+                        .filter(|value| !value.is_empty())
+                        .collect()
+                })
+            };
+
+            (values(param), values(&format!("{}!", param)), mode)
+        },
+        Err(_) => return Ok(()),
+    };
+
+    if mode == MatchMode::Regex {
+        for value in included.iter().chain(excluded.iter()) {
+            if conn.query("SELECT '' ~* $1", &[value]).is_err() {
+                return Err(format!("invalid regular expression in {}", param));
+            }
+        }
+    }
+
+    if !included.is_empty() {
+        filter.matches(column, &included, mode, false);
+    }
+    if !excluded.is_empty() {
+        filter.matches(column, &excluded, mode, true);
+    }
+
+    Ok(())
+}
+
+// Splits a comma separated list of values, "\," stands for a comma inside a value
+fn split_list(list: &str) -> Vec<String> {
+    let mut values = vec![String::new()];
+    let mut chars = list.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' if chars.peek() == Some(&',') => {
+                chars.next();
+                values.last_mut().unwrap().push(',');
+            },
+            ',' => values.push(String::new()),
+            c => values.last_mut().unwrap().push(c),
+        }
+    }
+
+    values
+}
+
+// Lists the taxonomy nodes one level below a path of (column, slug) pairs,
+// matching the country and every slug exactly
+fn taxonomy_level(conn: &Connection, country: &str, path: &[(&str, String)], column: &str) -> Result<(Vec<TaxonomyEntry>, Option<DateTime<UTC>>), ApiError> {
+    let mut conditions = vec!["lower(country) = lower($1)".to_string()];
+    let mut params: Vec<&ToSql> = vec![&country];
+
+    for &(parent, ref slug) in path {
+        params.push(slug);
+        conditions.push(format!("taxonomy_slug({}) = ${}", parent, params.len()));
+    }
+
+    let query = format!(
+        "SELECT {column}, taxonomy_slug({column}), min({column}_url), count(*), max(updated_at)
+         FROM product
+         WHERE {conditions}
+         GROUP BY {column}
+         ORDER BY {column} ASC",
+        column = column,
+        conditions = conditions.join(" AND "));
+
+    let mut entries = Vec::new();
+    let mut last_modified: Option<DateTime<UTC>> = None;
+    for row in &try!(conn.query(&query, &params)) {
+        entries.push(TaxonomyEntry {
+            name: try!(row.value(0)),
+            slug: try!(row.value(1)),
+            url: try!(row.value(2)),
+            products: try!(row.value(3)),
+        });
+        last_modified = cmp::max(last_modified, Some(try!(row.value(4))));
+    }
+
+    Ok((entries, last_modified))
+}
+
+fn json_response<T: Encodable>(value: &T) -> Result<Response, ApiError> {
+    let json_output = try!(json::encode(value));
+    let tag = etag(json_output.as_bytes());
+    let mut response = Response::with((status::Ok, json_output));
+    response.headers.set(headers::ContentType::json());
+    response.headers.set(tag);
+    Ok(response)
+}
+
+// Like json_response, with the time the rows behind the value last changed
+fn modified_json_response<T: Encodable>(value: &T, last_modified: Option<DateTime<UTC>>) -> Result<Response, ApiError> {
+    let mut response = try!(json_response(value));
+    if let Some(last_modified) = last_modified {
+        set_last_modified(&mut response, last_modified);
+    }
+    Ok(response)
+}
+
+fn departments_handler(req: &mut Request) -> Result<Response, ApiError> {
+    // Get database handle
+    let pool = try!(req.get::<PersistentRead<DatabasePool>>());
+    let conn = try!(pool.get());
+
+    let mut filter = QueryFilter::new();
+    if let Err(message) = match_param(req, &conn, &mut filter, "country", "country") {
+        return Err(ApiError::BadRequest(message));
+    }
+
+    let mut departments = Vec::new();
+    let mut last_modified: Option<DateTime<UTC>> = None;
+
+    for row in &try!(conn.query(&format!("SELECT department, max(updated_at) FROM product WHERE {} GROUP BY department", filter.sql()), &filter.params())) {
+        let department: String = try!(row.value(0));
+        departments.push(department);
+        last_modified = cmp::max(last_modified, Some(try!(row.value(1))));
+    }
+
+    modified_json_response(&departments, last_modified)
+}
+
+fn categories_handler(req: &mut Request) -> Result<Response, ApiError> {
+    // Get database handle
+    let pool = try!(req.get::<PersistentRead<DatabasePool>>());
+    let conn = try!(pool.get());
+
+    let mut filter = QueryFilter::new();
+    if let Err(message) = match_param(req, &conn, &mut filter, "department", "department") {
+        return Err(ApiError::BadRequest(message));
+    }
+
+    if let Err(message) = match_param(req, &conn, &mut filter, "country", "country") {
+        return Err(ApiError::BadRequest(message));
+    }
+
+    let mut categories = Vec::new();
+    let mut last_modified: Option<DateTime<UTC>> = None;
+
+    for row in &try!(conn.query(&format!("SELECT category, max(updated_at) FROM product WHERE {} GROUP BY category", filter.sql()), &filter.params())) {
+        let category: String = try!(row.value(0));
+        categories.push(category);
+        last_modified = cmp::max(last_modified, Some(try!(row.value(1))));
+    }
+
+    modified_json_response(&categories, last_modified)
+}
+
+fn subcategories_handler(req: &mut Request) -> Result<Response, ApiError> {
+    // Get database handle
+    let pool = try!(req.get::<PersistentRead<DatabasePool>>());
+    let conn = try!(pool.get());
+
+    let mut filter = QueryFilter::new();
+    if let Err(message) = match_param(req, &conn, &mut filter, "category", "category") {
+        return Err(ApiError::BadRequest(message));
+    }
+
+    if let Err(message) = match_param(req, &conn, &mut filter, "country", "country") {
+        return Err(ApiError::BadRequest(message));
+    }
+
+    let mut subcategories = Vec::new();
+    let mut last_modified: Option<DateTime<UTC>> = None;
+
+    for row in &try!(conn.query(&format!("SELECT subcategory, max(updated_at) FROM product WHERE {} GROUP BY subcategory", filter.sql()), &filter.params())) {
+        let subcategory: String = try!(row.value(0));
+        subcategories.push(subcategory);
+        last_modified = cmp::max(last_modified, Some(try!(row.value(1))));
+    }
+
+    modified_json_response(&subcategories, last_modified)
+}
+
+fn types_handler(req: &mut Request) -> Result<Response, ApiError> {
+    // Get database handle
+    let pool = try!(req.get::<PersistentRead<DatabasePool>>());
+    let conn = try!(pool.get());
+
+    let mut filter = QueryFilter::new();
+    for &param in &["country", "department", "category", "subcategory"] {
+        if let Err(message) = match_param(req, &conn, &mut filter, param, param) {
+            return Err(ApiError::BadRequest(message));
+        }
+    }
+
+    let mut types = Vec::new();
+    let mut last_modified: Option<DateTime<UTC>> = None;
+
+    for row in &try!(conn.query(&format!("SELECT typ, count(*), max(updated_at) FROM product WHERE {} GROUP BY typ ORDER BY count(*) DESC, typ ASC", filter.sql()), &filter.params())) {
+        types.push(TypeCount {
+            typ: try!(row.value(0)),
+            products: try!(row.value(1)),
+        });
+        last_modified = cmp::max(last_modified, Some(try!(row.value(2))));
+    }
+
+    modified_json_response(&types, last_modified)
+}
+
+fn taxonomy_handler(req: &mut Request) -> Result<Response, ApiError> {
+    // Get database handle
+    let pool = try!(req.get::<PersistentRead<DatabasePool>>());
+    let conn = try!(pool.get());
+
+    let mut filter = QueryFilter::new();
+    if let Err(message) = match_param(req, &conn, &mut filter, "country", "country") {
+        return Err(ApiError::BadRequest(message));
+    }
+
+    let mut departments: Vec<TaxonomyNode> = Vec::new();
+    let mut last_modified: Option<DateTime<UTC>> = None;
+
+    // One row per department, category and subcategory, each parent sorts
+    // right before its children
+    for row in &try!(conn.query(&format!(
+            "SELECT department, category, subcategory,
+                    min(department_url), min(category_url), min(subcategory_url),
+                    count(*), min(price_amount)::float8, max(price_amount)::float8,
+                    CASE WHEN count(DISTINCT currency) = 1 THEN min(currency) END,
+                    GROUPING(category), GROUPING(subcategory),
+                    taxonomy_slug(coalesce(subcategory, category, department)),
+                    max(updated_at)
+             FROM product
+             WHERE {}
+             GROUP BY GROUPING SETS ((department), (department, category), (department, category, subcategory))
+             ORDER BY department ASC, category ASC NULLS FIRST, subcategory ASC NULLS FIRST",
+            filter.sql()), &filter.params()
+        )) {
+        let is_department = try!(row.value::<_, i32>(10)) == 1;
+        last_modified = cmp::max(last_modified, Some(try!(row.value(13))));
+        let is_category = !is_department && try!(row.value::<_, i32>(11)) == 1;
+
+        let (name, url) = if is_department {
+            (try!(row.value(0)), try!(row.value(3)))
+        } else if is_category {
+            (try!(row.value(1)), try!(row.value(4)))
+        } else {
+            (try!(row.value(2)), try!(row.value(5)))
+        };
+
+        let node = TaxonomyNode {
+            name: name,
+            slug: try!(row.value(12)),
+            url: url,
+            products: try!(row.value(6)),
+            min_price: try!(row.value(7)),
+            max_price: try!(row.value(8)),
+            currency: try!(row.value(9)),
+            children: Vec::new(),
+        };
+
+        if is_department {
+            departments.push(node);
+        } else if let Some(department) = departments.last_mut() {
+            if is_category {
+                department.children.push(node);
+            } else if let Some(category) = department.children.last_mut() {
+                category.children.push(node);
+            }
+        }
+    }
+
+    modified_json_response(&departments, last_modified)
+}
+
+fn taxonomy_level_handler(req: &mut Request, column: &str) -> Result<Response, ApiError> {
+    // Get database handle
+    let pool = try!(req.get::<PersistentRead<DatabasePool>>());
+    let conn = try!(pool.get());
+
+    let (country, path) = {
+        let params = req.extensions.get::<Router>().unwrap();
+        let segment = |name: &str| {
+            percent_decode(params.find(name).unwrap_or("").as_bytes()).decode_utf8_lossy().into_owned()
+        };
+
+        let mut path = Vec::new();
+        for &parent in &["department", "category"] {
+            if parent == column {
+                break;
+            }
+            path.push((parent, segment(parent)));
+        }
+
+        (segment("country"), path)
+    };
+
+    let (entries, last_modified) = try!(taxonomy_level(&conn, &country, &path, column));
+
+    // An empty level below an existing parent cannot happen, so the path
+    // itself does not exist
+    if entries.is_empty() {
+        return Err(ApiError::NotFound("no such taxonomy path".to_string()));
+    }
+
+    modified_json_response(&entries, last_modified)
+}
+
+fn country_departments_handler(req: &mut Request) -> Result<Response, ApiError> {
+    taxonomy_level_handler(req, "department")
+}
+
+fn department_categories_handler(req: &mut Request) -> Result<Response, ApiError> {
+    taxonomy_level_handler(req, "category")
+}
+
+fn category_subcategories_handler(req: &mut Request) -> Result<Response, ApiError> {
+    taxonomy_level_handler(req, "subcategory")
+}
+
+fn products_handler(req: &mut Request) -> Result<Response, ApiError> {
+    // Get database handle
+    let pool = try!(req.get::<PersistentRead<DatabasePool>>());
+    let conn = try!(pool.get());
+
+    let fields = match requested_fields(req) {
+        Ok(fields) => fields,
+        Err(message) => return Err(ApiError::BadRequest(message)),
+    };
+
+    let mut filter = QueryFilter::new();
+    if let Err(message) = match_param(req, &conn, &mut filter, "department", "department") {
+        return Err(ApiError::BadRequest(message));
+    }
+
+    if let Err(message) = match_param(req, &conn, &mut filter, "category", "category") {
+        return Err(ApiError::BadRequest(message));
+    }
+
+    if let Err(message) = match_param(req, &conn, &mut filter, "subcategory", "subcategory") {
+        return Err(ApiError::BadRequest(message));
+    }
+
+    if let Err(message) = match_param(req, &conn, &mut filter, "country", "country") {
+        return Err(ApiError::BadRequest(message));
+    }
+
+    if let Err(message) = match_param(req, &conn, &mut filter, "type", "typ") {
+        return Err(ApiError::BadRequest(message));
+    }
+
+    if let Err(message) = match_param(req, &conn, &mut filter, "unit", "unit") {
+        return Err(ApiError::BadRequest(message));
+    }
+
+    if let Err(message) = match_param(req, &conn, &mut filter, "metric", "metric") {
+        return Err(ApiError::BadRequest(message));
+    }
+
+    let limit = match req.get_ref::<UrlEncodedQuery>() {
+        Ok(ref hashmap) => {
+            match hashmap.get("limit") {
+                Some(limit) => match limit[0].parse::<i64>() {
+                    Ok(limit) if limit > 0 && limit <= MAX_PAGE_LIMIT => limit,
+                    _ => return Err(ApiError::BadRequest("invalid limit".to_string())),
+                },
+                None => DEFAULT_PAGE_LIMIT,
+            }
+        },
+        Err(_) => DEFAULT_PAGE_LIMIT,
+    };
+
+    let offset = match req.get_ref::<UrlEncodedQuery>() {
+        Ok(ref hashmap) => {
+            match hashmap.get("offset") {
+                Some(offset) => match offset[0].parse::<i64>() {
+                    Ok(offset) if offset >= 0 => offset,
+                    _ => return Err(ApiError::BadRequest("invalid offset".to_string())),
+                },
+                None => 0,
+            }
+        },
+        Err(_) => 0,
+    };
 
-impl Key for DatabaseConnection { type Value = Connection; }
+    let min_price = match req.get_ref::<UrlEncodedQuery>() {
+        Ok(ref hashmap) => {
+            match hashmap.get("min_price") {
+                Some(min_price) => match min_price[0].parse::<f64>() {
+                    Ok(min_price) if min_price.is_finite() => Some(min_price),
+                    _ => return Err(ApiError::BadRequest("invalid min_price".to_string())),
+                },
+                None => None,
+            }
+        },
+        Err(_) => None,
+    };
 
-#[derive(RustcEncodable)]
-struct Product {
-    id: String,
-    name: String,
-    typ: String,
-    country: String,
-    unit: String,
-    price: String,
-    metric: String,
-    image_url: String,
-    url: String,
-	department: String,
-	category: String,
-	subcategory: String,
-	department_url: String,
-	category_url: String,
-	subcategory_url: String,
-	created_at: String,
-	updated_at: String,
-}
+    let max_price = match req.get_ref::<UrlEncodedQuery>() {
+        Ok(ref hashmap) => {
+            match hashmap.get("max_price") {
+                Some(max_price) => match max_price[0].parse::<f64>() {
+                    Ok(max_price) if max_price.is_finite() => Some(max_price),
+                    _ => return Err(ApiError::BadRequest("invalid max_price".to_string())),
+                },
+                None => None,
+            }
+        },
+        Err(_) => None,
+    };
 
-#[derive(RustcEncodable)]
-struct Market {
-    country: String,
-    products: Vec<Product>,
-}
+    let sort = match req.get_ref::<UrlEncodedQuery>() {
+        Ok(ref hashmap) => {
+            match hashmap.get("sort") {
+                Some(sort) => match sort_order(&sort[0]) {
+                    Some(sort) => sort,
+                    None => return Err(ApiError::BadRequest("invalid sort".to_string())),
+                },
+                None => sort_order("name").unwrap(),
+            }
+        },
+        Err(_) => sort_order("name").unwrap(),
+    };
 
-fn departments_handler(req: &mut Request) -> IronResult<Response> {
-    // Get database handle
-    let mutex = req.get::<Write<DatabaseConnection>>().unwrap();
-    let conn = mutex.lock().unwrap();
+    let requested_facets: Vec<String> = match req.get_ref::<UrlEncodedQuery>() {
+        Ok(ref hashmap) => {
+            match hashmap.get("facets") {
+                Some(facets) => facets.iter()
+                    .flat_map(|facets| facets.split(','))
+                    .map(|facet| facet.trim().to_string())
+                    .filter(|facet| !facet.is_empty())
+                    .collect(),
+                None => Vec::new(),
+            }
+        },
+        Err(_) => Vec::new(),
+    };
+
+    if let Some(facet) = requested_facets.iter().find(|facet| !FACETS.contains(&facet.as_str())) {
+        return Err(ApiError::BadRequest(format!("unknown facet {}", facet)));
+    }
 
-    let ref country = match req.get_ref::<UrlEncodedQuery>() {
+    let cursor = match req.get_ref::<UrlEncodedQuery>() {
         Ok(ref hashmap) => {
-            match hashmap.get("country") {
-                Some(country) => format!("%{}%", country[0]),
-                None => "%%".to_string(),
+            match hashmap.get("cursor") {
+                Some(cursor) => match decode_cursor(&cursor[0]) {
+                    Some(cursor) => {
+                        if cursor.0 != sort.name {
+                            return Err(ApiError::BadRequest("cursor belongs to a different sort".to_string()));
+                        }
+                        Some(cursor)
+                    },
+                    None => return Err(ApiError::BadRequest("invalid cursor".to_string())),
+                },
+                None => None,
             }
         },
-        Err(_) => "%%".to_string(),
+        Err(_) => None,
     };
 
-    let mut departments = Vec::new();
+    // Amounts in different currencies do not compare, so price filters and
+    // sorts need the matching products to share one, which a country gives
+    let by_price = min_price.is_some() || max_price.is_some() || sort.name == "price" || sort.name == "-price";
+    if by_price {
+        let currencies: i64 = try!(try!(conn.query(
+                &format!("SELECT count(DISTINCT currency) FROM product WHERE {} AND price_amount IS NOT NULL", filter.sql()),
+                &filter.params()
+            )).get(0).value(0));
+        if currencies > 1 {
+            return Err(ApiError::BadRequest("prices are in more than one currency, filter by country to compare them".to_string()));
+        }
+    }
 
-    for row in &conn.query("SELECT department FROM product WHERE country ILIKE $1 GROUP BY department", &[country]).unwrap() {
-        let department: String = row.get(0);
-        departments.push(department);
+    if let Some(min_price) = min_price {
+        let min_price = filter.bind(min_price);
+        filter.condition(format!("price_amount >= CAST({}::float8 AS numeric)", min_price));
+    }
+
+    if let Some(max_price) = max_price {
+        let max_price = filter.bind(max_price);
+        filter.condition(format!("price_amount <= CAST({}::float8 AS numeric)", max_price));
+    }
+
+    let total: i64 = try!(try!(conn.query(
+            &format!("SELECT count(*) FROM product WHERE {}", filter.sql()),
+            &filter.params()
+        )).get(0).value(0));
+
+    let facets = if requested_facets.is_empty() {
+        None
+    } else {
+        let facet = |name: &str, column: &str| -> Result<Option<Vec<FacetCount>>, ApiError> {
+            if requested_facets.iter().any(|facet| facet == name) {
+                Ok(Some(try!(facet_counts(&conn, &filter, column))))
+            } else {
+                Ok(None)
+            }
+        };
+
+        Some(Facets {
+            department: try!(facet("department", "department")),
+            category: try!(facet("category", "category")),
+            subcategory: try!(facet("subcategory", "subcategory")),
+            typ: try!(facet("type", "typ")),
+            country: try!(facet("country", "country")),
+            price: if requested_facets.iter().any(|facet| facet == "price") {
+                Some(try!(price_buckets(&conn, &filter)))
+            } else {
+                None
+            },
+        })
+    };
+
+    // Keyset pagination compares (sort key, id) with the last row of the
+    // previous page, the id breaks ties between rows with the same key
+    if let Some((_, ref key, ref id)) = cursor {
+        let key = filter.bind(key.clone());
+        let id = filter.bind(id.clone());
+        filter.condition(format!("({}, id) {} ({}::text::{}, {})",
+                                 sort.expression,
+                                 if sort.descending { "<" } else { ">" },
+                                 key,
+                                 sort.sql_type,
+                                 id));
+    }
+
+    // Fetch one extra row to find out whether there is a next page
+    let page_limit = filter.bind(limit + 1);
+    let mut query = format!("SELECT {}, id AS cursor_id, ({})::text AS sort_key, updated_at AS last_modified FROM product
+                             WHERE {}
+                             ORDER BY {} {dir}, id {dir}
+                             LIMIT {}",
+                            field_columns(&fields),
+                            sort.expression,
+                            filter.sql(),
+                            sort.expression,
+                            page_limit,
+                            dir = if sort.descending { "DESC" } else { "ASC" });
+
+    if cursor.is_none() {
+        query.push_str(&format!(" OFFSET {}", filter.bind(offset)));
+    }
+
+    let rows = try!(conn.query(&query, &filter.params()));
+
+    let mut products = Vec::new();
+    let mut last: Option<(Option<String>, String)> = None;
+    let mut last_modified: Option<DateTime<UTC>> = None;
+
+    for row in rows.iter().take(limit as usize) {
+        last = Some((try!(row.value("sort_key")), try!(row.value("cursor_id"))));
+        last_modified = cmp::max(last_modified, Some(try!(row.value("last_modified"))));
+        products.push(try!(product_json(&row, &fields)));
+    }
+
+    let next_cursor = match last {
+        Some((key, id)) if rows.len() as i64 > limit => Some(encode_cursor(sort.name, &key.unwrap_or_default(), &id)),
+        _ => None,
+    };
+
+    let page = ProductPage {
+        products: products,
+        total: total,
+        next_cursor: next_cursor,
+        facets: facets,
+    };
+
+    modified_json_response(&page, last_modified)
+}
+
+fn product_handler(req: &mut Request) -> Result<Response, ApiError> {
+    // Get database handle
+    let pool = try!(req.get::<PersistentRead<DatabasePool>>());
+    let conn = try!(pool.get());
+    
+    let mut products = Vec::new();
+
+    let fields = match requested_fields(req) {
+        Ok(fields) => fields,
+        Err(message) => return Err(ApiError::BadRequest(message)),
+    };
+
+    let ref ids = req.extensions.get::<Router>().unwrap().find("id").unwrap_or("/");
+    let ids_vec: Vec<&str> = ids.split(",").collect();
+    if ids_vec.len() > 1 {
+        let items: Vec<(String, i64)> = ids_vec.iter().map(|id| (id.to_string(), 1)).collect();
+        let (markets, last_modified) = try!(markets_for_items(&conn, &items, &fields));
+
+        return modified_json_response(&markets, last_modified);
+    }
+
+    let mut last_modified: Option<DateTime<UTC>> = None;
+
+    for row in &try!(conn.query(&format!("SELECT {}, updated_at AS last_modified FROM product WHERE id = $1", field_columns(&fields)), &[&ids])) {
+        last_modified = cmp::max(last_modified, Some(try!(row.value("last_modified"))));
+        products.push(try!(product_json(&row, &fields)));
     }
 
-    if let Ok(json_output) = json::encode(&departments) {
-        let mut response = Response::with((status::Ok, json_output));
-        response.headers.set(headers::AccessControlAllowOrigin::Value("*".to_string()));
-        return Ok(response);
+    if products.is_empty() {
+        return Err(ApiError::NotFound(format!("no product with id {}", ids)));
     }
 
-    Ok(Response::with((status::Ok)))
+    modified_json_response(&products, last_modified)
 }
 
-fn categories_handler(req: &mut Request) -> IronResult<Response> {
+fn product_handler_with_query(req: &mut Request) -> Result<Response, ApiError> {
     // Get database handle
-    let mutex = req.get::<Write<DatabaseConnection>>().unwrap();
-    let conn = mutex.lock().unwrap();
+    let pool = try!(req.get::<PersistentRead<DatabasePool>>());
+    let conn = try!(pool.get());
 
-    let ref department = match req.get_ref::<UrlEncodedQuery>() {
-        Ok(ref hashmap) => {
-            match hashmap.get("department") {
-                Some(department) => format!("%{}%", department[0]),
-                None => "%%".to_string(),
-            }
-        },
-        Err(_) => "%%".to_string(),
+    let fields = match requested_fields(req) {
+        Ok(fields) => fields,
+        Err(message) => return Err(ApiError::BadRequest(message)),
     };
 
-    let ref country = match req.get_ref::<UrlEncodedQuery>() {
+    let ids = match req.get_ref::<UrlEncodedQuery>() {
         Ok(ref hashmap) => {
-            match hashmap.get("country") {
-                Some(country) => format!("%{}%", country[0]),
-                None => "%%".to_string(),
+            match hashmap.get("id") {
+                Some(ids) if ids.len() > 0 => ids.clone(),
+                _ => return Err(ApiError::BadRequest("missing id".to_string())),
             }
         },
-        Err(_) => "%%".to_string(),
+        Err(_) => return Err(ApiError::BadRequest("missing id".to_string())),
     };
 
-    let mut categories = Vec::new();
+    if ids.len() > 1 {
+        let items: Vec<(String, i64)> = ids.iter().map(|id| (id.clone(), 1)).collect();
+        let (markets, last_modified) = try!(markets_for_items(&conn, &items, &fields));
 
-    for row in &conn.query("SELECT category FROM product WHERE country ILIKE $1 AND department ILIKE $2 GROUP BY category", &[country, department]).unwrap() {
-        let category: String = row.get(0);
-        categories.push(category);
+        return modified_json_response(&markets, last_modified);
+    }
+
+    let mut products = Vec::new();
+    let mut last_modified: Option<DateTime<UTC>> = None;
+
+    for row in &try!(conn.query(&format!("SELECT {}, updated_at AS last_modified FROM product WHERE id = $1", field_columns(&fields)), &[&ids[0]])) {
+        last_modified = cmp::max(last_modified, Some(try!(row.value("last_modified"))));
+        products.push(try!(product_json(&row, &fields)));
     }
 
-    if let Ok(json_output) = json::encode(&categories) {
-        let mut response = Response::with((status::Ok, json_output));
-        response.headers.set(headers::AccessControlAllowOrigin::Value("*".to_string()));
-        return Ok(response);
+    if products.is_empty() {
+        return Err(ApiError::NotFound(format!("no product with id {}", ids[0])));
     }
 
-    Ok(Response::with((status::Ok)))
+    modified_json_response(&products, last_modified)
 }
 
-fn subcategories_handler(req: &mut Request) -> IronResult<Response> {
+fn search_handler(req: &mut Request) -> Result<Response, ApiError> {
     // Get database handle
-    let mutex = req.get::<Write<DatabaseConnection>>().unwrap();
-    let conn = mutex.lock().unwrap();
+    let pool = try!(req.get::<PersistentRead<DatabasePool>>());
+    let conn = try!(pool.get());
 
-    let ref category = match req.get_ref::<UrlEncodedQuery>() {
+    let ref q = match req.get_ref::<UrlEncodedQuery>() {
         Ok(ref hashmap) => {
-            match hashmap.get("category") {
-                Some(category) => format!("%{}%", category[0]),
-                None => "%%".to_string(),
+            match hashmap.get("q") {
+                Some(q) if !q[0].trim().is_empty() => q[0].clone(),
+                _ => return Err(ApiError::BadRequest("missing q".to_string())),
             }
         },
-        Err(_) => "%%".to_string(),
+        Err(_) => return Err(ApiError::BadRequest("missing q".to_string())),
     };
 
-    let ref country = match req.get_ref::<UrlEncodedQuery>() {
+    let limit = match req.get_ref::<UrlEncodedQuery>() {
         Ok(ref hashmap) => {
-            match hashmap.get("country") {
-                Some(country) => format!("%{}%", country[0]),
-                None => "%%".to_string(),
+            match hashmap.get("limit") {
+                Some(limit) => match limit[0].parse::<i64>() {
+                    Ok(limit) if limit > 0 && limit <= MAX_PAGE_LIMIT => limit,
+                    _ => return Err(ApiError::BadRequest("invalid limit".to_string())),
+                },
+                None => DEFAULT_PAGE_LIMIT,
             }
         },
-        Err(_) => "%%".to_string(),
+        Err(_) => DEFAULT_PAGE_LIMIT,
     };
 
-    let mut subcategories = Vec::new();
+    let offset = match req.get_ref::<UrlEncodedQuery>() {
+        Ok(ref hashmap) => {
+            match hashmap.get("offset") {
+                Some(offset) => match offset[0].parse::<i64>() {
+                    Ok(offset) if offset >= 0 => offset,
+                    _ => return Err(ApiError::BadRequest("invalid offset".to_string())),
+                },
+                None => 0,
+            }
+        },
+        Err(_) => 0,
+    };
 
-    for row in &conn.query("SELECT subcategory FROM product WHERE category ILIKE $1 AND country ILIKE $2 GROUP BY subcategory", &[category, country]).unwrap() {
-        let subcategory: String = row.get(0);
-        subcategories.push(subcategory);
+    let mut filter = QueryFilter::new();
+    let q = filter.bind(q.clone());
+    if let Err(message) = match_param(req, &conn, &mut filter, "country", "product.country") {
+        return Err(ApiError::BadRequest(message));
     }
-
-    if let Ok(json_output) = json::encode(&subcategories) {
-        let mut response = Response::with((status::Ok, json_output));
-        response.headers.set(headers::AccessControlAllowOrigin::Value("*".to_string()));
-        return Ok(response);
+    let limit = filter.bind(limit);
+    let offset = filter.bind(offset);
+
+    // Only the page of matches gets a headline, ts_headline is slow
+    let query = format!(
+        "SELECT matches.*,
+                ts_headline(matches.config,
+                            concat_ws(' ', matches.name, matches.typ, matches.department, matches.category, matches.subcategory),
+                            matches.query,
+                            'StartSel=<mark>, StopSel=</mark>, MaxFragments=2') AS snippet
+         FROM (
+             SELECT {columns}, search.config, search.query, ts_rank({document}, search.query) AS rank
+             FROM product,
+                  LATERAL (SELECT {config} AS config) AS language,
+                  LATERAL (SELECT language.config, plainto_tsquery(language.config, {q}) AS query) AS search
+             WHERE {filter} AND {document} @@ {any_query} AND {document} @@ search.query
+             ORDER BY rank DESC, product.id ASC
+             LIMIT {limit} OFFSET {offset}
+         ) AS matches
+         ORDER BY matches.rank DESC, matches.id ASC",
+        columns = PRODUCT_COLUMNS,
+        document = search_document_sql(),
+        config = search_config_sql("product.country"),
+        any_query = search_any_query_sql(&q),
+        q = q,
+        filter = filter.sql(),
+        limit = limit,
+        offset = offset);
+
+    let mut results = Vec::new();
+    let mut last_modified: Option<DateTime<UTC>> = None;
+
+    for row in &try!(conn.query(&query, &filter.params())) {
+        last_modified = cmp::max(last_modified, Some(try!(row.value("updated_at"))));
+        results.push(SearchResult {
+            product: try!(product_from_row(&row)),
+            rank: try!(row.value("rank")),
+            snippet: try!(row.value("snippet")),
+        });
     }
 
-    Ok(Response::with((status::NotFound)))
+    modified_json_response(&results, last_modified)
 }
 
-fn products_handler(req: &mut Request) -> IronResult<Response> {
-    // Get database handle
-    let mutex = req.get::<Write<DatabaseConnection>>().unwrap();
-    let conn = mutex.lock().unwrap();
-
-    let ref department = match req.get_ref::<UrlEncodedQuery>() {
+fn suggest_handler(req: &mut Request) -> Result<Response, ApiError> {
+    let q = match req.get_ref::<UrlEncodedQuery>() {
         Ok(ref hashmap) => {
-            match hashmap.get("department") {
-                Some(department) => format!("%{}%", department[0]),
-                None => "%%".to_string(),
+            match hashmap.get("q") {
+                Some(q) => q[0].clone(),
+                None => return Err(ApiError::BadRequest("missing q".to_string())),
             }
         },
-        Err(_) => "%%".to_string(),
+        Err(_) => return Err(ApiError::BadRequest("missing q".to_string())),
     };
 
-    let ref category = match req.get_ref::<UrlEncodedQuery>() {
-        Ok(ref hashmap) => {
-            match hashmap.get("category") {
-                Some(category) => format!("%{}%", category[0]),
-                None => "%%".to_string(),
-            }
-        },
-        Err(_) => "%%".to_string(),
+    let country = match req.get_ref::<UrlEncodedQuery>() {
+        Ok(ref hashmap) => hashmap.get("country").map(|country| country[0].clone()),
+        Err(_) => None,
     };
 
-    let ref subcategory = match req.get_ref::<UrlEncodedQuery>() {
+    let limit = match req.get_ref::<UrlEncodedQuery>() {
         Ok(ref hashmap) => {
-            match hashmap.get("subcategory") {
-                Some(subcategory) => format!("%{}%", subcategory[0]),
-                None => "%%".to_string(),
+            match hashmap.get("limit") {
+                Some(limit) => match limit[0].parse::<usize>() {
+                    Ok(limit) if limit > 0 && limit <= MAX_SUGGEST_LIMIT => limit,
+                    _ => return Err(ApiError::BadRequest("invalid limit".to_string())),
+                },
+                None => DEFAULT_SUGGEST_LIMIT,
             }
         },
-        Err(_) => "%%".to_string(),
+        Err(_) => DEFAULT_SUGGEST_LIMIT,
+    };
+
+    let lock = try!(req.get::<State<SuggestCache>>());
+
+    let stale = match try!(lock.read()).checked_at {
+        Some(checked_at) => checked_at.elapsed() >= Duration::from_secs(SUGGEST_REFRESH_SECS),
+        None => true,
     };
 
-    let ref country = match req.get_ref::<UrlEncodedQuery>() {
+    if stale {
+        // Get database handle
+        let pool = try!(req.get::<PersistentRead<DatabasePool>>());
+        let conn = try!(pool.get());
+
+        let rows = try!(conn.query("SELECT count(*), max(updated_at) FROM product", &[]));
+        let row = rows.get(0);
+        let signature = Signature { rows: try!(row.value(0)), updated_at: try!(row.value(1)) };
+
+        let mut index = try!(lock.write());
+        if index.signature.as_ref() != Some(&signature) {
+            let mut entries = Vec::new();
+            for row in &try!(conn.query("SELECT name, typ, country, count(*) FROM product GROUP BY name, typ, country", &[])) {
+                entries.push((try!(row.value(0)), try!(row.value(1)), try!(row.value(2)), try!(row.value(3))));
+            }
+            *index = SuggestIndex::build(signature, entries);
+        } else {
+            index.checked_at = Some(Instant::now());
+        }
+    }
+
+    let index = try!(lock.read());
+    let suggestions = index.suggest(&q, country.as_ref().map(|country| country.as_str()), limit);
+    let last_modified = index.signature.as_ref().and_then(|signature| signature.updated_at);
+
+    modified_json_response(&suggestions, last_modified)
+}
+
+fn compare_handler(req: &mut Request) -> Result<Response, ApiError> {
+    // Get database handle
+    let pool = try!(req.get::<PersistentRead<DatabasePool>>());
+    let conn = try!(pool.get());
+
+    let id = req.extensions.get::<Router>().unwrap().find("id").unwrap_or("").to_string();
+
+    let currency = match req.get_ref::<UrlEncodedQuery>() {
         Ok(ref hashmap) => {
-            match hashmap.get("country") {
-                Some(country) => format!("%{}%", country[0]),
-                None => "%%".to_string(),
+            match hashmap.get("currency") {
+                Some(currency) => currency[0].to_uppercase(),
+                None => return Err(ApiError::BadRequest("missing currency".to_string())),
             }
         },
-        Err(_) => "%%".to_string(),
+        Err(_) => return Err(ApiError::BadRequest("missing currency".to_string())),
     };
 
-    let mut products = Vec::new();
+    let rates = try!(exchange_rates(&conn));
+    if !rates.contains_key(&currency) {
+        return Err(ApiError::BadRequest("unknown currency".to_string()));
+    }
 
-    for row in &conn.query(
-            "SELECT * FROM product
-             WHERE department ILIKE $1 AND category ILIKE $2 AND subcategory ILIKE $3 AND country ILIKE $4
-             ORDER BY name ASC",
-            &[department, category, subcategory, country]
-        ).unwrap() {
-        let product = Product {
-            id: row.get(0),
-            name: row.get(1),
-            typ: row.get(2),
-            country: row.get(3),
-            price: row.get(4),
-            unit: row.get(5),
-            metric: row.get(6),
-            url: row.get(7),
-            image_url: row.get(8),
-            department: row.get(9),
-            category: row.get(10),
-            subcategory: row.get(11),
-            department_url: row.get(12),
-            category_url: row.get(13),
-            subcategory_url: row.get(14),
-            created_at: "".to_string(),
-            updated_at: "".to_string(),
+    // Converted prices change with the rates as much as with the products
+    let mut last_modified: Option<DateTime<UTC>> = try!(try!(conn.query(
+            "SELECT max(updated_at) FROM exchange_rate", &[]
+        )).get(0).value(0));
+    let mut prices = Vec::new();
+
+    for row in &try!(conn.query(&format!("SELECT {} FROM product WHERE id = $1", PRODUCT_COLUMNS), &[&id])) {
+        last_modified = cmp::max(last_modified, Some(try!(row.value("updated_at"))));
+        let product = try!(product_from_row(&row));
+        let converted_amount = match (product.price_amount, product.currency.clone()) {
+            (Some(amount), Some(from)) => convert(amount, &from, &currency, &rates),
+            _ => None,
         };
 
-        let created_at: DateTime<UTC> = row.get(15);
-        let updated_at: DateTime<UTC> = row.get(16);
-
-        let mut product = product;
-        product.created_at = created_at.to_rfc2822();
-        product.updated_at = updated_at.to_rfc2822();
+        prices.push(ComparedPrice {
+            product: product,
+            converted_amount: converted_amount,
+            difference_from_median: None,
+        });
+    }
 
-        products.push(product);
+    if prices.is_empty() {
+        return Err(ApiError::NotFound(format!("no product with id {}", id)));
     }
 
-    if let Ok(json_output) = json::encode(&products) {
-        let mut response = Response::with((status::Ok, json_output));
-        response.headers.set(headers::AccessControlAllowOrigin::Value("*".to_string()));
-        return Ok(response);
+    // Cheapest first, markets without a converted price last
+    prices.sort_by(|a, b| match (a.converted_amount, b.converted_amount) {
+        (Some(a), Some(b)) => a.partial_cmp(&b).unwrap(),
+        (Some(_), None) => Ordering::Less,
+        (None, Some(_)) => Ordering::Greater,
+        (None, None) => a.product.country.cmp(&b.product.country),
+    });
+
+    let amounts: Vec<f64> = prices.iter().filter_map(|price| price.converted_amount).collect();
+    let median = median(&amounts);
+
+    if let Some(median) = median {
+        for price in &mut prices {
+            price.difference_from_median = price.converted_amount
+                .map(|amount| round_cents((amount - median) / median * 100.0));
+        }
     }
 
-    Ok(Response::with((status::NotFound)))
+    let comparison = Comparison {
+        id: id,
+        currency: currency,
+        median: median,
+        prices: prices,
+    };
+
+    modified_json_response(&comparison, last_modified)
 }
 
-fn product_handler(req: &mut Request) -> IronResult<Response> {
+fn basket_handler(req: &mut Request) -> Result<Response, ApiError> {
+    let mut body = String::new();
+    if req.body.by_ref().take(MAX_BODY_BYTES).read_to_string(&mut body).is_err() {
+        return Err(ApiError::BadRequest("unreadable body".to_string()));
+    }
+
+    let basket: Basket = match json::decode(&body) {
+        Ok(basket) => basket,
+        Err(_) => return Err(ApiError::BadRequest("expected {\"items\": [{\"id\": ..., \"quantity\": ...}]}".to_string())),
+    };
+
+    if basket.items.is_empty() || basket.items.len() > MAX_BASKET_ITEMS {
+        return Err(ApiError::BadRequest("invalid number of items".to_string()));
+    }
+    if basket.items.iter().any(|item| item.quantity <= 0) {
+        return Err(ApiError::BadRequest("invalid quantity".to_string()));
+    }
+
     // Get database handle
-    let mutex = req.get::<Write<DatabaseConnection>>().unwrap();
-    let conn = mutex.lock().unwrap();
-    
-    let mut products = Vec::new();
+    let pool = try!(req.get::<PersistentRead<DatabasePool>>());
+    let conn = try!(pool.get());
+
+    let rates = try!(exchange_rates(&conn));
+    let currency = basket.currency.map(|currency| currency.to_uppercase());
+    if let Some(ref currency) = currency {
+        if !rates.contains_key(currency) {
+            return Err(ApiError::BadRequest("unknown currency".to_string()));
+        }
+    }
 
-    let ref ids = req.extensions.get::<Router>().unwrap().find("id").unwrap_or("/");
-    let ids_vec: Vec<&str> = ids.split(",").collect();
-    if ids_vec.len() == 0 {
-        return Ok(Response::with(status::BadRequest));
-    } else if ids_vec.len() == 1 {
-        for row in &conn.query("SELECT * FROM product WHERE id = $1", &[&ids]).unwrap() {
-            let product = Product {
-                id: row.get(0),
-                name: row.get(1),
-                typ: row.get(2),
-                country: row.get(3),
-                price: row.get(4),
-                unit: row.get(5),
-                metric: row.get(6),
-                url: row.get(7),
-                image_url: row.get(8),
-                department: row.get(9),
-                category: row.get(10),
-                subcategory: row.get(11),
-                department_url: row.get(12),
-                category_url: row.get(13),
-                subcategory_url: row.get(14),
-                created_at: "".to_string(),
-                updated_at: "".to_string(),
-            };
+    let items: Vec<(String, i64)> = basket.items.into_iter().map(|item| (item.id, item.quantity)).collect();
+    let fields: Vec<&ProductField> = PRODUCT_FIELDS.iter().collect();
+    let (mut markets, _) = try!(markets_for_items(&conn, &items, &fields));
 
-            let created_at: DateTime<UTC> = row.get(15);
-            let updated_at: DateTime<UTC> = row.get(16);
+    // Totals are only comparable in a common currency, USD unless requested
+    let comparison_currency = currency.clone().unwrap_or("USD".to_string());
+    let mut cheapest: Option<(String, f64)> = None;
 
-            let mut product = product;
-            product.created_at = created_at.to_rfc2822();
-            product.updated_at = updated_at.to_rfc2822();
+    for market in &mut markets {
+        let compared = match (market.total, market.currency.clone()) {
+            (Some(total), Some(from)) => convert(total, &from, &comparison_currency, &rates),
+            _ => None,
+        };
 
-            products.push(product);
+        if currency.is_some() {
+            market.converted_total = compared;
         }
 
-        if let Ok(json_output) = json::encode(&products) {
-            let mut response = Response::with((status::Ok, json_output));
-            response.headers.set(headers::AccessControlAllowOrigin::Value("*".to_string()));
-            return Ok(response);
-        }
-    } else if ids_vec.len() > 1 {
-        let mut index = 0;
-        let mut ids_str = String::new();
-        for id in &ids_vec {
-            ids_str.push_str(&format!("'{}'", id));
-            if index < ids_vec.len() - 1 {
-                ids_str.push_str(",");
+        if let Some(compared) = compared {
+            let is_cheaper = match cheapest {
+                Some((_, cheapest_total)) => compared < cheapest_total,
+                None => true,
+            };
+            if market.missing.is_empty() && is_cheaper {
+                cheapest = Some((market.country.clone(), compared));
             }
-            index += 1;
         }
+    }
 
-        let mut countries = Vec::new();
-        for row in &conn.query(&format!("SELECT country FROM product WHERE id IN ({}) GROUP BY country", &ids_str), &[]).unwrap() {
-            let country: String = row.get(0);
-            countries.push(country);
-        }
+    let comparison = BasketComparison {
+        currency: currency,
+        cheapest_market: cheapest.map(|(country, _)| country),
+        markets: markets,
+    };
 
-        let mut markets = Vec::new();
-        for country in countries {
-            let mut products = Vec::<Product>::new();
-            for row in &conn.query(&format!("SELECT * FROM product WHERE id IN ({}) AND country LIKE $1", &ids_str), &[&country]).unwrap() {
-                let product = Product {
-                    id: row.get(0),
-                    name: row.get(1),
-                    typ: row.get(2),
-                    country: row.get(3),
-                    price: row.get(4),
-                    unit: row.get(5),
-                    metric: row.get(6),
-                    url: row.get(7),
-                    image_url: row.get(8),
-                    department: row.get(9),
-                    category: row.get(10),
-                    subcategory: row.get(11),
-                    department_url: row.get(12),
-                    category_url: row.get(13),
-                    subcategory_url: row.get(14),
-                    created_at: "".to_string(),
-                    updated_at: "".to_string(),
-                };
-
-                let created_at: DateTime<UTC> = row.get(15);
-                let updated_at: DateTime<UTC> = row.get(16);
-
-                let mut product = product;
-                product.created_at = created_at.to_rfc2822();
-                product.updated_at = updated_at.to_rfc2822();
-
-                products.push(product);
-            }
+    json_response(&comparison)
+}
+
+fn price_history_handler(req: &mut Request) -> Result<Response, ApiError> {
+    // Get database handle
+    let pool = try!(req.get::<PersistentRead<DatabasePool>>());
+    let conn = try!(pool.get());
 
-            markets.push(Market{ country: country, products: products });
+    let id = req.extensions.get::<Router>().unwrap().find("id").unwrap_or("").to_string();
+
+    let mut filter = QueryFilter::new();
+    let id = filter.bind(id);
+    if let Err(message) = match_param(req, &conn, &mut filter, "country", "country") {
+        return Err(ApiError::BadRequest(message));
+    }
+
+    let mut histories: Vec<PriceHistory> = Vec::new();
+    let mut last_modified: Option<DateTime<UTC>> = None;
+
+    for row in &try!(conn.query(&format!(
+            "SELECT country, currency, price, price_amount::float8, recorded_at FROM price_history
+             WHERE id = {} AND {}
+             ORDER BY country ASC, recorded_at ASC",
+            id, filter.sql()), &filter.params()
+        )) {
+        let country: String = try!(row.value(0));
+        let recorded_at: DateTime<UTC> = try!(row.value(4));
+        last_modified = cmp::max(last_modified, Some(recorded_at));
+
+        if histories.last().map_or(true, |history| history.country != country) {
+            histories.push(PriceHistory {
+                country: country,
+                currency: None,
+                min: None,
+                max: None,
+                current: None,
+                points: Vec::new(),
+            });
         }
 
-        if let Ok(json_output) = json::encode(&markets) {
-            let mut response = Response::with((status::Ok, json_output));
-            response.headers.set(headers::AccessControlAllowOrigin::Value("*".to_string()));
-            return Ok(response);
+        let history = histories.last_mut().unwrap();
+        let price_amount: Option<f64> = try!(row.value(3));
+
+        if let Some(amount) = price_amount {
+            history.min = Some(history.min.map_or(amount, |min| min.min(amount)));
+            history.max = Some(history.max.map_or(amount, |max| max.max(amount)));
         }
+        history.currency = try!(row.value(1));
+        history.current = price_amount;
+        history.points.push(PricePoint {
+            price: try!(row.value(2)),
+            price_amount: price_amount,
+            recorded_at: recorded_at.to_rfc2822(),
+        });
     }
 
-    if let Ok(json_output) = json::encode(&products) {
-        let mut response = Response::with((status::Ok, json_output));
-        response.headers.set(headers::AccessControlAllowOrigin::Value("*".to_string()));
-        return Ok(response);
+    if histories.is_empty() {
+        return Err(ApiError::NotFound("no price history for this product".to_string()));
     }
-    
-    Ok(Response::with((status::NotFound, "")))
+
+    modified_json_response(&histories, last_modified)
 }
 
-fn product_handler_with_query(req: &mut Request) -> IronResult<Response> {
+fn price_errors_handler(req: &mut Request) -> Result<Response, ApiError> {
     // Get database handle
-    let mutex = req.get::<Write<DatabaseConnection>>().unwrap();
-    let conn = mutex.lock().unwrap();
+    let pool = try!(req.get::<PersistentRead<DatabasePool>>());
+    let conn = try!(pool.get());
 
-    let ids = match req.get_ref::<UrlEncodedQuery>() {
-        Ok(ref hashmap) => {
-            match hashmap.get("id") {
-                Some(ids) => {
-                    if ids.len() > 0 {
-                        ids
-                    } else {
-                        return Ok(Response::with((status::NotFound, "")));
-                    }
-                },
-                None => return Ok(Response::with((status::NotFound, ""))),
-            }
-        },
-        Err(_) => return Ok(Response::with((status::NotFound, ""))),
-    };
-
-    if ids.len() == 1 {
-        let mut products = Vec::new();
-
-        for row in &conn.query("SELECT * FROM product WHERE id = $1", &[&ids[0]]).unwrap() {
-            let product = Product {
-                id: row.get(0),
-                name: row.get(1),
-                typ: row.get(2),
-                country: row.get(3),
-                price: row.get(4),
-                unit: row.get(5),
-                metric: row.get(6),
-                url: row.get(7),
-                image_url: row.get(8),
-                department: row.get(9),
-                category: row.get(10),
-                subcategory: row.get(11),
-                department_url: row.get(12),
-                category_url: row.get(13),
-                subcategory_url: row.get(14),
-                created_at: "".to_string(),
-                updated_at: "".to_string(),
-            };
+    let mut filter = QueryFilter::new();
+    if let Err(message) = match_param(req, &conn, &mut filter, "country", "country") {
+        return Err(ApiError::BadRequest(message));
+    }
+
+    let mut errors = Vec::new();
+    let mut last_modified: Option<DateTime<UTC>> = None;
+
+    for row in &try!(conn.query(&format!("SELECT id, country, price, price_error, updated_at FROM product WHERE price_error IS NOT NULL AND {} ORDER BY country, id", filter.sql()), &filter.params())) {
+        errors.push(PriceError {
+            id: try!(row.value(0)),
+            country: try!(row.value(1)),
+            price: try!(row.value(2)),
+            error: try!(row.value(3)),
+        });
+        last_modified = cmp::max(last_modified, Some(try!(row.value(4))));
+    }
 
-            let created_at: DateTime<UTC> = row.get(15);
-            let updated_at: DateTime<UTC> = row.get(16);
+    modified_json_response(&errors, last_modified)
+}
 
-            let mut product = product;
-            product.created_at = created_at.to_rfc2822();
-            product.updated_at = updated_at.to_rfc2822();
+// Admin endpoints are only routed when admin.token is set, and want it as a
+// bearer token
+fn authorize_admin(req: &mut Request) -> Result<(), ApiError> {
+    let token = try!(req.get::<PersistentRead<AdminToken>>());
+    let given = match req.headers.get::<headers::Authorization<headers::Bearer>>() {
+        Some(authorization) => authorization.0.token.clone(),
+        None => return Err(ApiError::Unauthorized("missing bearer token".to_string())),
+    };
 
-            products.push(product);
-        }
+    // Compares every byte so that the time taken does not tell how much of
+    // the token was right
+    let matches = given.len() == token.len() &&
+        given.bytes().zip(token.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0;
+    if !matches {
+        return Err(ApiError::Unauthorized("invalid bearer token".to_string()));
+    }
 
-        if let Ok(json_output) = json::encode(&products) {
-            let mut response = Response::with((status::Ok, json_output));
-            response.headers.set(headers::AccessControlAllowOrigin::Value("*".to_string()));
-            return Ok(response);
-        }
-    } else if ids.len() > 1 {
-        let mut index = 0;
-        let mut ids_str = String::new();
-        for id in ids {
-            ids_str.push_str(&format!("'{}'", id));
-            if index < ids.len() - 1 {
-                ids_str.push_str(",");
-            }
-            index += 1;
-        }
+    Ok(())
+}
 
-        let mut countries = Vec::new();
-        for row in &conn.query(&format!("SELECT country FROM product WHERE id in ({}) GROUP BY country", &ids_str), &[]).unwrap() {
-            let country: String = row.get(0);
-            countries.push(country);
-        }
+fn cache_stats_handler(req: &mut Request) -> Result<Response, ApiError> {
+    try!(authorize_admin(req));
 
-        let mut markets = Vec::new();
-        for country in countries {
-            let mut products = Vec::<Product>::new();
-            for row in &conn.query(&format!("SELECT * FROM product WHERE id in ({}) AND country LIKE $1", &ids_str), &[&country]).unwrap() {
-                let product = Product {
-                    id: row.get(0),
-                    name: row.get(1),
-                    typ: row.get(2),
-                    country: row.get(3),
-                    price: row.get(4),
-                    unit: row.get(5),
-                    metric: row.get(6),
-                    url: row.get(7),
-                    image_url: row.get(8),
-                    department: row.get(9),
-                    category: row.get(10),
-                    subcategory: row.get(11),
-                    department_url: row.get(12),
-                    category_url: row.get(13),
-                    subcategory_url: row.get(14),
-                    created_at: "".to_string(),
-                    updated_at: "".to_string(),
-                };
-
-                let created_at: DateTime<UTC> = row.get(15);
-                let updated_at: DateTime<UTC> = row.get(16);
-
-                let mut product = product;
-                product.created_at = created_at.to_rfc2822();
-                product.updated_at = updated_at.to_rfc2822();
-
-                products.push(product);
-            }
+    let lock = try!(req.get::<State<ResponseCache>>());
+    let stats = try!(lock.read()).stats();
 
-            markets.push(Market{ country: country, products: products });
-        }
+    json_response(&stats)
+}
 
-        if let Ok(json_output) = json::encode(&markets) {
-            let mut response = Response::with((status::Ok, json_output));
-            response.headers.set(headers::AccessControlAllowOrigin::Value("*".to_string()));
-            return Ok(response);
-        }
-    }
-    
-    Ok(Response::with((status::NotFound, "")))
+fn cache_purge_handler(req: &mut Request) -> Result<Response, ApiError> {
+    try!(authorize_admin(req));
+
+    let lock = try!(req.get::<State<ResponseCache>>());
+    let purged = try!(lock.write()).purge();
+    println!("Purged {} cached responses", purged);
+
+    json_response(&CachePurge { purged: purged })
 }
 
 fn print_usage(program: &str, opts: Options) {
@@ -526,11 +1946,25 @@ fn print_usage(program: &str, opts: Options) {
 }
 
 fn main() {
+    if let Err(error) = run() {
+        println!("{}", error);
+        process::exit(1);
+    }
+}
+
+fn run() -> Result<(), ApiError> {
     // Parse program arguments
     let args: Vec<String> = env::args().collect();
     let program = args[0].clone();
 
     let mut opts = Options::new();
+    opts.optopt("",
+                "config",
+                "read settings from a TOML file, also set by IKEA_CONFIG",
+                "FILE");
+    opts.optflag("",
+                 "print-config",
+                 "print the settings in effect, without secrets, and exit");
     opts.optopt("",
                 "dbhost",
                 "set database host",
@@ -539,14 +1973,54 @@ fn main() {
                 "dbport",
                 "set database port",
                 "DBPORT");
+    opts.optopt("",
+                "dbname",
+                "set database name",
+                "DBNAME");
     opts.optopt("",
                 "dbuser",
                 "set database username",
                 "DBUSER");
     opts.optopt("",
                 "dbpass",
-                "set database password",
+                "set database password, visible to other users of the machine",
                 "DBPASS");
+    opts.optopt("",
+                "dbpassfile",
+                "read database password from a file",
+                "FILE");
+    opts.optopt("",
+                "dbsslmode",
+                "set database TLS mode: disable, prefer, require or verify-full",
+                "DBSSLMODE");
+    opts.optopt("",
+                "dbsslrootcert",
+                "set CA bundle to verify the database certificate with",
+                "FILE");
+    opts.optopt("",
+                "dbsslcert",
+                "set client certificate to present to the database",
+                "FILE");
+    opts.optopt("",
+                "dbsslkey",
+                "set key of the client certificate",
+                "FILE");
+    opts.optopt("",
+                "dbpoolmin",
+                "set number of idle database connections to keep open",
+                "DBPOOLMIN");
+    opts.optopt("",
+                "dbpoolmax",
+                "set maximum number of database connections",
+                "DBPOOLMAX");
+    opts.optopt("",
+                "dbtimeout",
+                "set seconds a request waits for a free database connection",
+                "DBTIMEOUT");
+    opts.optopt("",
+                "dbretries",
+                "set attempts to reach the database at startup, 0 retries forever",
+                "DBRETRIES");
     opts.optopt("",
                 "host",
                 "set server host",
@@ -555,62 +2029,227 @@ fn main() {
                 "port",
                 "set server port",
                 "PORT");
+    opts.optopt("",
+                "tls-cert",
+                "serve HTTPS with this PEM certificate chain, reloaded when it changes",
+                "FILE");
+    opts.optopt("",
+                "tls-key",
+                "set PEM private key of the HTTPS certificate",
+                "FILE");
+    opts.optopt("",
+                "http-redirect-port",
+                "also listen for plain HTTP on this port and redirect it to HTTPS",
+                "PORT");
+    opts.optopt("",
+                "import-rates",
+                "import exchange rates from a currency,rate file and exit",
+                "FILE");
+    opts.optflag("",
+                 "parse-prices",
+                 "parse changed product prices, record price history, report failures and exit");
     opts.optflag("h", "help", "print this help menu");
 
     let matches = match opts.parse(&args[1..]) {
         Ok(m) => m,
-        Err(f) => panic!(f.to_string()),
+        Err(f) => return Err(ApiError::Config(f.to_string())),
     };
     if matches.opt_present("h") {
         print_usage(&program, opts);
-        return;
+        return Ok(());
     }
 
-    let dbhost: String = match matches.opt_str("dbhost") {
-        Some(t) => t,
-        None => "localhost".to_string(),
+    let config = try!(Config::load(&matches));
+    if matches.opt_present("print-config") {
+        print!("{}", config);
+        return Ok(());
+    }
+
+    let dbuser = percent_encode(try!(config.value::<String>("database.user")).as_bytes(), USERINFO_ENCODE_SET).collect::<String>();
+
+    let dbpass: String = match try!(config.database_password()) {
+        Some(t) => format!(":{}", percent_encode(t.as_bytes(), USERINFO_ENCODE_SET)),
+        None => "".to_string(),
     };
 
-    let dbport: String = match matches.opt_str("dbport") {
-        Some(t) => t,
-        None => "5432".to_string(),
+    let dbname: String = match config.get("database.name") {
+        Some(t) => format!("/{}", percent_encode(t.as_bytes(), PATH_SEGMENT_ENCODE_SET)),
+        None => "".to_string(),
     };
 
-    let dbuser: String = match matches.opt_str("dbuser") {
-        Some(t) => percent_encode(t.as_bytes(), USERINFO_ENCODE_SET).collect::<String>(),
-        None => "postgres".to_string(),
+    let url = format!("postgres://{}{}@{}:{}{}",
+                      dbuser,
+                      dbpass,
+                      try!(config.value::<String>("database.host")),
+                      try!(config.value::<u16>("database.port")),
+                      dbname);
+
+    let pool_options = PoolOptions {
+        min_idle: try!(config.parse("database.pool_min")),
+        max_size: try!(config.value("database.pool_max")),
+        checkout_timeout: Duration::from_secs(try!(config.value("database.timeout"))),
+        connect_attempts: try!(config.value("database.retries")),
     };
 
-    let dbpass: String = match matches.opt_str("dbpass") {
-        Some(t) => format!(":{}", percent_encode(t.as_bytes(), USERINFO_ENCODE_SET)),
-        None => "".to_string(),
+    let tls_options = TlsOptions {
+        mode: match TlsMode::parse(&try!(config.value::<String>("database.sslmode"))) {
+            Some(mode) => mode,
+            None => return Err(ApiError::Config("database.sslmode must be disable, prefer, require or verify-full".to_string())),
+        },
+        root_cert: config.get("database.sslrootcert").map(|t| t.to_string()),
+        client_cert: config.get("database.sslcert").map(|t| t.to_string()),
+        client_key: config.get("database.sslkey").map(|t| t.to_string()),
     };
 
-    let conn = Connection::connect(format!("postgres://{}{}@{}:{}", dbuser, dbpass, dbhost, dbport).as_str(), SslMode::None).unwrap();
+    let pool = try!(connect_pool(&url, &pool_options, &tls_options));
+    let conn = try!(pool.get());
+
+    try!(setup_database(&conn));
+
+    if let Some(path) = matches.opt_str("import-rates") {
+        match import_exchange_rates(&conn, &path) {
+            Ok(count) => println!("Imported {} exchange rates", count),
+            Err(error) => return Err(ApiError::Config(format!("failed to import exchange rates: {}", error))),
+        }
+        return Ok(());
+    }
+
+    let (parsed, errors) = try!(parse_prices(&conn));
+    println!("Parsed {} prices, {} failed", parsed, errors.len());
+
+    let recorded = try!(record_price_history(&conn));
+    println!("Recorded {} price changes", recorded);
+
+    if matches.opt_present("parse-prices") {
+        for error in &errors {
+            println!("{}\t{}\t{:?}\t{}", error.country, error.id, error.price, error.error);
+        }
+        return Ok(());
+    }
+
+    // Return the startup connection to the pool before serving
+    drop(conn);
+
+    watch_prices(pool.clone());
 
     let mut router = Router::new();
-    router.get("/departments", departments_handler);
-    router.get("/categories", categories_handler);
-    router.get("/subcategories", subcategories_handler);
-    router.get("/products", products_handler);
-    router.get("/product", product_handler_with_query);
-    router.get("/product/:id", product_handler);
+    router.get("/departments", Cached(ApiHandler(departments_handler)));
+    router.get("/categories", Cached(ApiHandler(categories_handler)));
+    router.get("/subcategories", Cached(ApiHandler(subcategories_handler)));
+    router.get("/types", Cached(ApiHandler(types_handler)));
+    router.get("/taxonomy", Cached(ApiHandler(taxonomy_handler)));
+    router.get("/countries/:country/departments", Cached(ApiHandler(country_departments_handler)));
+    router.get("/countries/:country/departments/:department/categories", Cached(ApiHandler(department_categories_handler)));
+    router.get("/countries/:country/departments/:department/categories/:category/subcategories", Cached(ApiHandler(category_subcategories_handler)));
+    router.get("/products", Cached(ApiHandler(products_handler)));
+    router.get("/product", Cached(ApiHandler(product_handler_with_query)));
+    router.get("/product/:id", Cached(ApiHandler(product_handler)));
+    router.get("/product/:id/history", Cached(ApiHandler(price_history_handler)));
+    router.get("/search", Cached(ApiHandler(search_handler)));
+    router.get("/suggest", ApiHandler(suggest_handler));
+    router.get("/prices/errors", Cached(ApiHandler(price_errors_handler)));
+    router.get("/compare/:id", Cached(ApiHandler(compare_handler)));
+    router.post("/basket", ApiHandler(basket_handler));
+
+    let admin_token = config.get("admin.token").map(|token| token.to_string());
+    if admin_token.is_some() {
+        router.get("/admin/cache", ApiHandler(cache_stats_handler));
+        router.delete("/admin/cache", ApiHandler(cache_purge_handler));
+    }
+
+    let mut allowed_methods = Vec::new();
+    for method in config.list("cors.allowed_methods") {
+        match method.to_uppercase().parse() {
+            Ok(method) => allowed_methods.push(method),
+            Err(_) => return Err(ApiError::Config(format!("invalid method {} in cors.allowed_methods", method))),
+        }
+    }
+
+    let cors_options = CorsOptions {
+        allowed_origins: config.list("cors.allowed_origins"),
+        allowed_methods: allowed_methods,
+        allowed_headers: config.list("cors.allowed_headers"),
+        exposed_headers: config.list("cors.exposed_headers"),
+        allow_credentials: try!(config.value("cors.allow_credentials")),
+        max_age: try!(config.parse("cors.max_age")),
+    };
 
     let mut chain = Chain::new(router);
-    chain.link(Write::<DatabaseConnection>::both(conn));
+    chain.link(PersistentRead::<DatabasePool>::both(pool));
+    chain.link(State::<SuggestCache>::both(SuggestIndex::new()));
+    chain.link(State::<ResponseCache>::both(Cache::new(try!(config.value("cache.max_entries")),
+                                                       try!(config.value("cache.max_bytes")),
+                                                       Duration::from_secs(try!(config.value("cache.ttl"))))));
+    if let Some(admin_token) = admin_token {
+        chain.link(PersistentRead::<AdminToken>::both(admin_token));
+    }
+    chain.link_before(RequestIds::new());
+    chain.link_after(ErrorRenderer);
+    chain.link_after(ConditionalGet);
+    chain.link_after(RequestIds::new());
+    chain.link_after(Cors::new(cors_options));
+    chain.link_after(Compression::new(try!(config.value("compression.min_size"))));
+
+    let host: String = try!(config.value("server.host"));
+    let port: u16 = try!(config.value("server.port"));
+    let address = format!("{}:{}", host, port);
 
-    let host: String = match matches.opt_str("host") {
-        Some(t) => t,
-        None => "localhost".to_string(),
+    let (cert, key) = match (config.get("server.tls_cert"), config.get("server.tls_key")) {
+        (Some(cert), Some(key)) => (cert, key),
+        (None, None) => {
+            if config.get("server.http_redirect_port").is_some() {
+                return Err(ApiError::Config("server.http_redirect_port needs server.tls_cert and server.tls_key".to_string()));
+            }
+
+            println!("Serving at http://{}", address);
+            return match Iron::new(chain).http(address.as_str()) {
+                Ok(_) => Ok(()),
+                Err(error) => Err(ApiError::Internal(format!("cannot listen on {}: {}", address, error))),
+            };
+        },
+        _ => return Err(ApiError::Config("server.tls_cert and server.tls_key must be set together".to_string())),
     };
 
-    let port: String = match matches.opt_str("port") {
-        Some(t) => t,
-        None => "8080".to_string(),
+    let tls = try!(ReloadableTls::new(cert, key));
+    tls.watch(Duration::from_secs(CERTIFICATE_CHECK_SECS));
+
+    // Kept until the HTTPS listener returns, dropping it waits for the
+    // redirect listener to finish
+    let _redirect = match try!(config.parse::<u16>("server.http_redirect_port")) {
+        Some(redirect_port) => {
+            let redirect_address = format!("{}:{}", host, redirect_port);
+            println!("Redirecting http://{} to HTTPS", redirect_address);
+
+            match Iron::new(RedirectToHttps { port: port }).http(redirect_address.as_str()) {
+                Ok(listening) => Some(listening),
+                Err(error) => return Err(ApiError::Internal(format!("cannot listen on {}: {}", redirect_address, error))),
+            }
+        },
+        None => None,
     };
 
-    let address = format!("{}:{}", host, port);
-    println!("Serving at {}", address);
+    println!("Serving at https://{}", address);
+    try!(https::serve(chain, &address, tls));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::split_list;
+
+    #[test]
+    fn escaped_commas_stay_in_their_value() {
+        assert_eq!(split_list("a\\,b,c"), vec!["a,b", "c"]);
+    }
+
+    #[test]
+    fn other_backslashes_are_kept() {
+        assert_eq!(split_list("a\\b,c\\"), vec!["a\\b", "c\\"]);
+    }
 
-    Iron::new(chain).http(address.as_str()).unwrap();
+    #[test]
+    fn empty_items_are_kept_for_the_caller_to_drop() {
+        assert_eq!(split_list("a,,b,"), vec!["a", "", "b", ""]);
+        assert_eq!(split_list(""), vec![""]);
+    }
 }
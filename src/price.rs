@@ -0,0 +1,209 @@
+// Parsing of the price strings the spider scrapes from each market.
+//
+// Markets format prices very differently ("1.299,-", "S$ 49.90", "RM1,299",
+// "1 299 kr", "CHF 1'299.–"), so the amount is recovered from the digits and
+// separators alone and the currency comes from the market, never the string.
+
+// Whole amounts have to fit the 12 integer digits of the numeric(14, 2)
+// column they are stored in
+const MAX_WHOLE: i64 = 1_000_000_000_000;
+
+// ISO 4217 currency of each market
+const CURRENCIES: &'static [(&'static str, &'static str)] = &[
+    ("ae", "AED"),
+    ("at", "EUR"),
+    ("au", "AUD"),
+    ("be", "EUR"),
+    ("ca", "CAD"),
+    ("ch", "CHF"),
+    ("cn", "CNY"),
+    ("cz", "CZK"),
+    ("de", "EUR"),
+    ("dk", "DKK"),
+    ("es", "EUR"),
+    ("fi", "EUR"),
+    ("fr", "EUR"),
+    ("gb", "GBP"),
+    ("hk", "HKD"),
+    ("hu", "HUF"),
+    ("id", "IDR"),
+    ("ie", "EUR"),
+    ("in", "INR"),
+    ("it", "EUR"),
+    ("jp", "JPY"),
+    ("kr", "KRW"),
+    ("my", "MYR"),
+    ("nl", "EUR"),
+    ("no", "NOK"),
+    ("pl", "PLN"),
+    ("pt", "EUR"),
+    ("ro", "RON"),
+    ("ru", "RUB"),
+    ("se", "SEK"),
+    ("sg", "SGD"),
+    ("th", "THB"),
+    ("tr", "TRY"),
+    ("tw", "TWD"),
+    ("uk", "GBP"),
+    ("us", "USD"),
+];
+
+#[derive(Debug, PartialEq)]
+pub struct Price {
+    // Amount in hundredths of the currency unit
+    pub cents: i64,
+    pub currency: &'static str,
+}
+
+impl Price {
+    // Decimal representation suitable for a NUMERIC column
+    pub fn amount(&self) -> String {
+        format!("{}.{:02}", self.cents / 100, self.cents % 100)
+    }
+}
+
+pub fn currency_for_country(country: &str) -> Option<&'static str> {
+    let country = country.trim().to_lowercase();
+    CURRENCIES.iter()
+        .find(|&&(code, _)| code == country)
+        .map(|&(_, currency)| currency)
+}
+
+pub fn parse_price(raw: &str, country: &str) -> Result<Price, String> {
+    let currency = match currency_for_country(country) {
+        Some(currency) => currency,
+        None => return Err(format!("no currency known for country '{}'", country)),
+    };
+
+    // Anything after a slash is a unit ("/st", "/m²")
+    let raw = raw.split('/').next().unwrap_or("");
+
+    // Keep digits and separators, a trailing ",-" or ".–" means no decimals
+    let mut text: String = raw.chars()
+        .filter(|c| c.is_digit(10) || *c == '.' || *c == ',' || *c == '\'' || *c == '-' || *c == '–')
+        .collect();
+    text = text.trim_matches(|c| c == '-' || c == '–').to_string();
+    text = text.trim_right_matches(|c| c == '.' || c == ',').to_string();
+
+    if text.is_empty() || !text.chars().any(|c| c.is_digit(10)) {
+        return Err("no digits".to_string());
+    }
+    if text.contains('-') || text.contains('–') {
+        return Err("looks like a price range".to_string());
+    }
+
+    let text = text.replace('\'', "");
+    let decimal_separator = decimal_separator(&text);
+
+    let (whole, fraction) = match decimal_separator {
+        Some(separator) => {
+            let position = text.rfind(separator).unwrap();
+            (&text[..position], &text[position + 1..])
+        },
+        None => (&text[..], ""),
+    };
+
+    let whole: String = whole.chars().filter(|c| c.is_digit(10)).collect();
+    if whole.is_empty() && fraction.is_empty() {
+        return Err("no digits".to_string());
+    }
+    if fraction.len() > 2 {
+        return Err("more than two decimals".to_string());
+    }
+
+    let whole: i64 = match whole.parse() {
+        Ok(whole) if whole < MAX_WHOLE => whole,
+        Err(_) if whole.is_empty() => 0,
+        _ => return Err("amount out of range".to_string()),
+    };
+    let fraction: i64 = match fraction.len() {
+        0 => 0,
+        1 => fraction.parse::<i64>().unwrap() * 10,
+        _ => fraction.parse::<i64>().unwrap(),
+    };
+
+    Ok(Price { cents: whole * 100 + fraction, currency: currency })
+}
+
+// Works out which of ',' and '.' separates decimals. When both appear the
+// last one does. When only one appears, a single occurrence followed by one
+// or two digits is a decimal separator and anything else groups thousands.
+fn decimal_separator(text: &str) -> Option<char> {
+    let last_comma = text.rfind(',');
+    let last_dot = text.rfind('.');
+
+    let candidate = match (last_comma, last_dot) {
+        (Some(comma), Some(dot)) => return Some(if comma > dot { ',' } else { '.' }),
+        (Some(_), None) => ',',
+        (None, Some(_)) => '.',
+        (None, None) => return None,
+    };
+
+    let occurrences = text.matches(candidate).count();
+    let digits_after = text.len() - text.rfind(candidate).unwrap() - 1;
+
+    if occurrences == 1 && (digits_after == 1 || digits_after == 2) {
+        Some(candidate)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cents(raw: &str, country: &str) -> i64 {
+        parse_price(raw, country).unwrap().cents
+    }
+
+    #[test]
+    fn dash_means_no_decimals() {
+        assert_eq!(parse_price("1.299,-", "se"), Ok(Price { cents: 129900, currency: "SEK" }));
+        assert_eq!(cents("CHF 1'299.–", "ch"), 129900);
+    }
+
+    #[test]
+    fn currency_symbols_are_ignored() {
+        assert_eq!(parse_price("S$ 49.90", "sg"), Ok(Price { cents: 4990, currency: "SGD" }));
+        assert_eq!(parse_price("RM1,299", "my"), Ok(Price { cents: 129900, currency: "MYR" }));
+        assert_eq!(cents("1 299 kr", "se"), 129900);
+    }
+
+    #[test]
+    fn single_separator_before_one_or_two_digits_is_decimal() {
+        assert_eq!(cents("49,9", "de"), 4990);
+        assert_eq!(cents("1,299.50", "us"), 129950);
+        assert_eq!(cents("1.299,50", "de"), 129950);
+        assert_eq!(cents("1.299", "de"), 129900);
+    }
+
+    #[test]
+    fn units_after_a_slash_are_dropped() {
+        assert_eq!(cents("$4.99/pack", "us"), 499);
+    }
+
+    #[test]
+    fn ranges_are_rejected() {
+        assert_eq!(parse_price("99-199 kr", "se"), Err("looks like a price range".to_string()));
+        assert_eq!(parse_price("$9.99 – $19.99", "us"), Err("looks like a price range".to_string()));
+    }
+
+    #[test]
+    fn three_decimals_are_rejected() {
+        assert_eq!(parse_price("1,299.999", "us"), Err("more than two decimals".to_string()));
+    }
+
+    #[test]
+    fn amounts_the_column_cannot_hold_are_rejected() {
+        assert_eq!(cents("999,999,999,999.99", "us"), 99999999999999);
+        assert_eq!(parse_price("1,000,000,000,000", "us"), Err("amount out of range".to_string()));
+        assert_eq!(parse_price("99999999999999999999999", "us"), Err("amount out of range".to_string()));
+    }
+
+    #[test]
+    fn unknown_markets_and_missing_digits_are_rejected() {
+        assert!(parse_price("49.90", "xx").is_err());
+        assert_eq!(parse_price("Sold out", "us"), Err("no digits".to_string()));
+    }
+}
@@ -0,0 +1,313 @@
+// In-memory index of product names used for autocomplete.
+//
+// Every distinct (name, typ, country) is an entry. Entries are reachable by
+// prefix through a sorted map of word-aligned keys ("hemnes bed frame",
+// "bed frame", "frame") and by similarity through a trigram inverted index,
+// which is what makes "Billi" find "BILLY". Without a country the entries of
+// every market that sells a (name, typ) make up a single suggestion.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::time::Instant;
+
+use chrono::*;
+
+const MIN_SIMILARITY: f32 = 0.3;
+
+pub struct Entry {
+    pub name: String,
+    pub typ: String,
+    pub country: String,
+    pub products: i64,
+    label: String,
+    // Entries of the same (name, typ) in other countries share the group
+    group: usize,
+}
+
+#[derive(RustcEncodable)]
+pub struct Suggestion {
+    pub name: String,
+    pub typ: String,
+    // Only set when the suggestions were asked for one country
+    pub country: Option<String>,
+    pub products: i64,
+    pub score: f32,
+    pub exact_prefix: bool,
+}
+
+// What the index was built from, used to notice that the table has changed
+#[derive(PartialEq)]
+pub struct Signature {
+    pub rows: i64,
+    pub updated_at: Option<DateTime<UTC>>,
+}
+
+pub struct SuggestIndex {
+    pub signature: Option<Signature>,
+    pub checked_at: Option<Instant>,
+    entries: Vec<Entry>,
+    // Products of each (name, typ) across all countries
+    group_products: Vec<i64>,
+    prefixes: BTreeMap<String, Vec<usize>>,
+    trigrams: HashMap<String, Vec<usize>>,
+}
+
+impl SuggestIndex {
+    pub fn new() -> SuggestIndex {
+        SuggestIndex {
+            signature: None,
+            checked_at: None,
+            entries: Vec::new(),
+            group_products: Vec::new(),
+            prefixes: BTreeMap::new(),
+            trigrams: HashMap::new(),
+        }
+    }
+
+    pub fn build(signature: Signature, entries: Vec<(String, String, String, i64)>) -> SuggestIndex {
+        let mut index = SuggestIndex::new();
+        index.signature = Some(signature);
+        index.checked_at = Some(Instant::now());
+
+        let mut groups: HashMap<(String, String), usize> = HashMap::new();
+        for (name, typ, country, products) in entries {
+            let label = normalize(&format!("{} {}", name, typ));
+            let position = index.entries.len();
+
+            let words: Vec<&str> = label.split(' ').collect();
+            for start in 0..words.len() {
+                let key = words[start..].join(" ");
+                index.prefixes.entry(key).or_insert_with(Vec::new).push(position);
+            }
+
+            for trigram in trigrams(&label) {
+                index.trigrams.entry(trigram).or_insert_with(Vec::new).push(position);
+            }
+
+            let next_group = groups.len();
+            let group = *groups.entry((name.clone(), typ.clone())).or_insert(next_group);
+            if group == index.group_products.len() {
+                index.group_products.push(0);
+            }
+            index.group_products[group] += products;
+
+            index.entries.push(Entry {
+                name: name,
+                typ: typ,
+                country: country.to_lowercase(),
+                products: products,
+                label: label,
+                group: group,
+            });
+        }
+
+        index
+    }
+
+    // Prefix matches come first, ordered by how many products share the
+    // name, then the closest trigram matches fill up the remaining slots
+    pub fn suggest(&self, query: &str, country: Option<&str>, limit: usize) -> Vec<Suggestion> {
+        let query = normalize(query);
+        if query.is_empty() {
+            return Vec::new();
+        }
+
+        let country = country.map(|country| country.to_lowercase());
+        let in_country = |entry: &Entry| match country {
+            Some(ref country) => entry.country == *country,
+            None => true,
+        };
+        // What a suggestion stands for, one entry or one (name, typ)
+        let key = |position: usize| match country {
+            Some(_) => position,
+            None => self.entries[position].group,
+        };
+        let products = |position: usize| match country {
+            Some(_) => self.entries[position].products,
+            None => self.group_products[self.entries[position].group],
+        };
+
+        let mut seen = HashSet::new();
+        let mut prefixed = Vec::new();
+
+        for (prefix, positions) in self.prefixes.range(query.clone()..) {
+            if !prefix.starts_with(&query) {
+                break;
+            }
+            for &position in positions {
+                if in_country(&self.entries[position]) && seen.insert(key(position)) {
+                    prefixed.push(position);
+                }
+            }
+        }
+
+        prefixed.sort_by(|&a, &b| {
+            products(b).cmp(&products(a)).then(self.entries[a].label.len().cmp(&self.entries[b].label.len()))
+        });
+
+        let mut suggestions: Vec<Suggestion> = prefixed.into_iter()
+            .take(limit)
+            .map(|position| self.suggestion(position, products(position), country.is_some(), 1.0, true))
+            .collect();
+
+        if suggestions.len() >= limit {
+            return suggestions;
+        }
+
+        let query_trigrams = trigrams(&query);
+        let mut shared: HashMap<usize, usize> = HashMap::new();
+        for trigram in &query_trigrams {
+            if let Some(positions) = self.trigrams.get(trigram) {
+                for &position in positions {
+                    if !seen.contains(&key(position)) {
+                        *shared.entry(position).or_insert(0) += 1;
+                    }
+                }
+            }
+        }
+
+        let mut fuzzy: Vec<(usize, f32)> = shared.into_iter()
+            .filter(|&(position, _)| in_country(&self.entries[position]))
+            .map(|(position, _)| (position, word_similarity(&query_trigrams, &self.entries[position].label)))
+            .filter(|&(_, score)| score >= MIN_SIMILARITY)
+            .collect();
+
+        fuzzy.sort_by(|a, b| {
+            b.1.partial_cmp(&a.1).unwrap()
+                .then(products(b.0).cmp(&products(a.0)))
+        });
+
+        let remaining = limit - suggestions.len();
+        suggestions.extend(fuzzy.into_iter()
+            .filter(|&(position, _)| seen.insert(key(position)))
+            .take(remaining)
+            .map(|(position, score)| self.suggestion(position, products(position), country.is_some(), score, false)));
+
+        suggestions
+    }
+
+    fn suggestion(&self, position: usize, products: i64, in_country: bool, score: f32, exact_prefix: bool) -> Suggestion {
+        let entry = &self.entries[position];
+        Suggestion {
+            name: entry.name.clone(),
+            typ: entry.typ.clone(),
+            country: if in_country { Some(entry.country.clone()) } else { None },
+            products: products,
+            score: score,
+            exact_prefix: exact_prefix,
+        }
+    }
+}
+
+// Lowercases and collapses everything that is not alphanumeric into single
+// spaces, so "HEMNES  Bed-frame" and "hemnes bed frame" compare equal
+fn normalize(text: &str) -> String {
+    let lowered = text.to_lowercase();
+    let words: Vec<&str> = lowered.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .collect();
+    words.join(" ")
+}
+
+// Best similarity between the query and any run of consecutive words in the
+// label, like pg_trgm's word_similarity, so that a long type does not water
+// down a close match on the name
+fn word_similarity(query: &HashSet<String>, label: &str) -> f32 {
+    let words: Vec<HashSet<String>> = label.split(' ').map(trigrams).collect();
+    let mut best = 0.0;
+
+    for start in 0..words.len() {
+        let mut span = HashSet::new();
+        for word in &words[start..] {
+            span.extend(word.iter().cloned());
+            let shared = query.intersection(&span).count();
+            let score = shared as f32 / (query.len() + span.len() - shared) as f32;
+            if score > best {
+                best = score;
+            }
+        }
+    }
+
+    best
+}
+
+// Trigrams of every word padded like pg_trgm does, two spaces in front and
+// one behind, so that short words still produce a few trigrams
+fn trigrams(text: &str) -> HashSet<String> {
+    let mut set = HashSet::new();
+    for word in text.split(' ') {
+        let padded: Vec<char> = format!("  {} ", word).chars().collect();
+        for window in padded.windows(3) {
+            set.insert(window.iter().cloned().collect());
+        }
+    }
+    set
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(name: &str, typ: &str, country: &str, products: i64) -> (String, String, String, i64) {
+        (name.to_string(), typ.to_string(), country.to_string(), products)
+    }
+
+    fn index() -> SuggestIndex {
+        SuggestIndex::build(Signature { rows: 4, updated_at: None }, vec![
+            entry("BILLY", "bookcase", "us", 10),
+            entry("BILLY", "bookcase with glass doors", "us", 3),
+            entry("HEMNES", "bed frame", "us", 5),
+            entry("HEMNES", "chest of 3 drawers", "us", 2),
+        ])
+    }
+
+    #[test]
+    fn exact_name_is_a_prefix_match() {
+        let suggestions = index().suggest("BILLY", None, 10);
+        assert_eq!(suggestions.len(), 2);
+        assert!(suggestions.iter().all(|suggestion| suggestion.name == "BILLY" && suggestion.exact_prefix));
+        assert_eq!(suggestions[0].typ, "bookcase");
+    }
+
+    #[test]
+    fn misspelled_name_matches_by_trigrams() {
+        let suggestions = index().suggest("Billi", None, 10);
+        assert_eq!(suggestions.len(), 2);
+        assert!(suggestions.iter().all(|suggestion| suggestion.name == "BILLY" && !suggestion.exact_prefix));
+        assert!(suggestions.iter().all(|suggestion| suggestion.score >= MIN_SIMILARITY));
+    }
+
+    #[test]
+    fn name_and_type_words_match_as_a_prefix() {
+        let suggestions = index().suggest("hemnes bed", None, 10);
+        assert_eq!(suggestions[0].name, "HEMNES");
+        assert_eq!(suggestions[0].typ, "bed frame");
+        assert!(suggestions[0].exact_prefix);
+    }
+
+    #[test]
+    fn other_countries_are_left_out() {
+        assert!(index().suggest("Billi", Some("SE"), 10).is_empty());
+    }
+
+    #[test]
+    fn markets_are_merged_without_a_country() {
+        let index = SuggestIndex::build(Signature { rows: 3, updated_at: None }, vec![
+            entry("BILLY", "bookcase", "us", 10),
+            entry("BILLY", "bookcase", "se", 4),
+            entry("BILLY", "bookcase with glass doors", "se", 3),
+        ]);
+
+        for query in &["billy", "Billi"] {
+            let suggestions = index.suggest(query, None, 10);
+            assert_eq!(suggestions.len(), 2);
+            assert_eq!(suggestions[0].typ, "bookcase");
+            assert_eq!(suggestions[0].products, 14);
+            assert_eq!(suggestions[0].country, None);
+        }
+
+        let suggestions = index.suggest("billy", Some("se"), 10);
+        assert_eq!(suggestions.len(), 2);
+        assert_eq!(suggestions[0].products, 4);
+        assert_eq!(suggestions[0].country, Some("se".to_string()));
+    }
+}
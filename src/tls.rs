@@ -0,0 +1,109 @@
+// TLS for the connections to Postgres.
+//
+// The modes follow libpq's sslmode: disable never encrypts, prefer encrypts
+// when the server supports it, require always encrypts and verify-full also
+// checks the server certificate against the CA bundle and the host name.
+
+use std::error::Error;
+
+use openssl::ssl::{Ssl, SslContext, SslMethod, SslStream, SSL_VERIFY_NONE, SSL_VERIFY_PEER,
+                   SSL_OP_NO_SSLV2, SSL_OP_NO_SSLV3, SSL_OP_NO_COMPRESSION};
+use openssl::x509::X509FileType;
+use openssl_verify;
+use postgres::io::{NegotiateSsl, Stream, StreamWrapper};
+use r2d2_postgres::SslMode;
+
+use error::ApiError;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum TlsMode {
+    Disable,
+    Prefer,
+    Require,
+    VerifyFull,
+}
+
+impl TlsMode {
+    pub fn parse(mode: &str) -> Option<TlsMode> {
+        match mode {
+            "disable" => Some(TlsMode::Disable),
+            "prefer" => Some(TlsMode::Prefer),
+            "require" => Some(TlsMode::Require),
+            "verify-full" => Some(TlsMode::VerifyFull),
+            _ => None,
+        }
+    }
+}
+
+pub struct TlsOptions {
+    pub mode: TlsMode,
+    // PEM bundle of CAs trusted to sign the server certificate, the system
+    // store is used when verifying without one
+    pub root_cert: Option<String>,
+    // PEM client certificate and key, for servers that authenticate clients
+    pub client_cert: Option<String>,
+    pub client_key: Option<String>,
+}
+
+#[derive(Debug)]
+struct PostgresTls {
+    context: SslContext,
+    verify_host: bool,
+}
+
+impl NegotiateSsl for PostgresTls {
+    fn negotiate_ssl(&self, host: &str, stream: Stream) -> Result<Box<StreamWrapper>, Box<Error + Sync + Send>> {
+        let mut ssl = try!(Ssl::new(&self.context));
+        try!(ssl.set_hostname(host));
+        if self.verify_host {
+            let host = host.to_owned();
+            ssl.set_verify_callback(SSL_VERIFY_PEER, move |p, x| openssl_verify::verify_callback(&host, p, x));
+        }
+        let stream = try!(SslStream::connect(ssl, stream));
+        Ok(Box::new(stream))
+    }
+}
+
+pub fn ssl_mode(options: &TlsOptions) -> Result<SslMode, ApiError> {
+    if options.client_cert.is_some() != options.client_key.is_some() {
+        return Err(ApiError::Config("a client certificate needs both a certificate and a key".to_string()));
+    }
+
+    let tls = match options.mode {
+        TlsMode::Disable => return Ok(SslMode::None),
+        _ => try!(negotiator(options)),
+    };
+
+    match options.mode {
+        TlsMode::Prefer => Ok(SslMode::Prefer(Box::new(tls))),
+        _ => Ok(SslMode::Require(Box::new(tls))),
+    }
+}
+
+fn negotiator(options: &TlsOptions) -> Result<PostgresTls, ApiError> {
+    let config_error = |path: &str, err: &Error| ApiError::Config(format!("{}: {}", path, err));
+
+    let mut context = try!(SslContext::new(SslMethod::Sslv23).map_err(|e| ApiError::Internal(e.to_string())));
+    context.set_options(SSL_OP_NO_SSLV2 | SSL_OP_NO_SSLV3 | SSL_OP_NO_COMPRESSION);
+
+    // Like libpq, require only checks the chain when given a CA bundle
+    let verify = options.mode == TlsMode::VerifyFull || options.root_cert.is_some();
+
+    match options.root_cert {
+        Some(ref path) => try!(context.set_CA_file(path).map_err(|e| config_error(path, &e))),
+        None if verify => try!(context.set_default_verify_paths().map_err(|e| ApiError::Internal(e.to_string()))),
+        None => {},
+    }
+    context.set_verify(if verify { SSL_VERIFY_PEER } else { SSL_VERIFY_NONE }, None);
+
+    if let (&Some(ref cert), &Some(ref key)) = (&options.client_cert, &options.client_key) {
+        try!(context.set_certificate_file(cert, X509FileType::PEM).map_err(|e| config_error(cert, &e)));
+        try!(context.set_private_key_file(key, X509FileType::PEM).map_err(|e| config_error(key, &e)));
+        try!(context.check_private_key().map_err(|e| config_error(key, &e)));
+    }
+
+    Ok(PostgresTls {
+        context: context,
+        verify_host: options.mode == TlsMode::VerifyFull,
+    })
+}